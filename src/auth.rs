@@ -0,0 +1,207 @@
+use crate::config::{Config, Tag};
+use crate::db::get_collection;
+use crate::util::result::{Error, ResultExt};
+use crate::util::variables::{ADMIN_TOKEN, IP_HASH_SALT, TRUSTED_PROXIES};
+
+use actix_web::http::HeaderMap;
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac, NewMac};
+use log::warn;
+use mongodb::bson::{doc, Document};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build HTTP client.")
+});
+
+/// Token presented by the client.
+enum Token<'a> {
+    Session(&'a str),
+    Bot(&'a str),
+}
+
+impl Token<'_> {
+    fn header(&self) -> (&'static str, &str) {
+        match self {
+            Token::Session(token) => ("X-Session-Token", token),
+            Token::Bot(token) => ("X-Bot-Token", token),
+        }
+    }
+}
+
+/// User or bot making a request.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Uploader {
+    pub id: String,
+    #[serde(default)]
+    pub bot: bool,
+}
+
+fn find_token(headers: &HeaderMap) -> Option<Token<'_>> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    header("X-Session-Token")
+        .map(Token::Session)
+        .or_else(|| header("X-Bot-Token").map(Token::Bot))
+}
+
+/// Session or bot token sent with a request, without checking it.
+pub fn unverified_token(headers: &HeaderMap) -> Option<&str> {
+    find_token(headers).map(|token| match token {
+        Token::Session(token) | Token::Bot(token) => token,
+    })
+}
+
+/// Look the token up in the `sessions` or `bots` collection.
+async fn verify_with_database(token: Token<'_>) -> Result<Uploader, Error> {
+    let (collection, field, bot) = match token {
+        Token::Session(token) => ("sessions", token, false),
+        Token::Bot(token) => ("bots", token, true),
+    };
+
+    let document = get_collection::<Document>(collection)
+        .find_one(doc! { "token": field }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::Unauthenticated)?;
+
+    let id = if bot {
+        document.get_str("_id")
+    } else {
+        document.get_str("user_id")
+    }
+    .context(Error::DatabaseError)?;
+
+    Ok(Uploader {
+        id: id.to_string(),
+        bot,
+    })
+}
+
+/// Forward the token to the verification endpoint,
+/// which should reply with the uploader as JSON.
+async fn verify_with_endpoint(url: &str, token: Token<'_>) -> Result<Uploader, Error> {
+    let (name, value) = token.header();
+    let response = CLIENT
+        .get(url)
+        .header(name, value)
+        .send()
+        .await
+        .context(Error::AuthUnavailable)?;
+
+    match response.status().as_u16() {
+        200..=299 => response.json().await.context(Error::AuthUnavailable),
+        401 | 403 => Err(Error::Unauthenticated),
+        _ => Err(Error::AuthUnavailable),
+    }
+}
+
+/// Whether we've warned about forwarded addresses being ignored.
+static WARNED_UNTRUSTED: AtomicBool = AtomicBool::new(false);
+
+fn is_trusted(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|proxy| proxy.contains(ip))
+}
+
+/// Address of the client making a request.
+///
+/// `X-Forwarded-For` is only believed for hops added by
+/// trusted proxies, otherwise clients could pick their own.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    let mut ip = peer?.ip();
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+
+    if !forwarded.is_empty() && !is_trusted(&ip) && !WARNED_UNTRUSTED.swap(true, Ordering::Relaxed)
+    {
+        warn!(
+            "Ignoring X-Forwarded-For from {}, add your reverse proxy to AUTUMN_TRUSTED_PROXIES.",
+            ip
+        );
+    }
+
+    // Walk back from the nearest hop until we reach one we don't trust.
+    for hop in forwarded.into_iter().rev() {
+        if !is_trusted(&ip) {
+            break;
+        }
+
+        ip = hop;
+    }
+
+    Some(ip)
+}
+
+/// Hash the client's address so uploads from the same
+/// address can be matched without storing it.
+pub fn client_ip_hash(req: &HttpRequest) -> Option<String> {
+    let ip = client_ip(req.peer_addr(), req.headers())?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(IP_HASH_SALT.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(ip.to_string().as_bytes());
+    Some(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Only allow requests carrying the admin token.
+pub fn require_admin(req: &HttpRequest) -> Result<(), Error> {
+    let expected = ADMIN_TOKEN.as_ref().ok_or(Error::Unauthenticated)?;
+    let provided = req
+        .headers()
+        .get("X-Admin-Token")
+        .map(|v| v.as_bytes())
+        .ok_or(Error::Unauthenticated)?;
+
+    // Compare in constant time.
+    let matches = provided.len() == expected.len()
+        && provided
+            .iter()
+            .zip(expected.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::Unauthenticated)
+    }
+}
+
+/// Identify who is making the request, if anyone.
+pub async fn authenticate(req: &HttpRequest) -> Result<Option<Uploader>, Error> {
+    let token = match find_token(req.headers()) {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let uploader = if let Some(url) = &Config::global().auth.verify_url {
+        verify_with_endpoint(url, token).await?
+    } else {
+        verify_with_database(token).await?
+    };
+
+    Ok(Some(uploader))
+}
+
+/// Identify who is uploading to a tag,
+/// rejecting anonymous uploads if the tag requires it.
+pub async fn authenticate_upload(req: &HttpRequest, tag: &Tag) -> Result<Option<Uploader>, Error> {
+    let uploader = authenticate(req).await?;
+    if tag.require_auth && uploader.is_none() {
+        return Err(Error::Unauthenticated);
+    }
+
+    Ok(uploader)
+}
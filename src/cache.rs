@@ -0,0 +1,299 @@
+use crate::config::Config;
+use crate::util::lru::Lru;
+use crate::util::variables::CACHE_PATH;
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+/// Cache of resized image variants kept on local disk.
+///
+/// Variants are keyed by their ETag, which already uniquely identifies
+/// the file id, target dimensions and output format. Once the cache grows
+/// past its configured size, the least recently used variants are evicted.
+pub struct ResizeCache {
+    root: PathBuf,
+    max_size: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Size of each cached variant.
+    entries: Lru<String, u64>,
+    /// Total size of all cached variants.
+    total_size: u64,
+}
+
+impl State {
+    fn touch(&mut self, key: &str) -> Option<u64> {
+        self.entries.get_mut(key).copied()
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        if let Some(previous) = self.entries.insert(key, size) {
+            self.total_size -= previous;
+        }
+
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        if let Some(size) = self.entries.remove(key) {
+            self.total_size -= size;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove least recently used entries until we fit within `max_size`.
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.total_size > max_size {
+            match self.entries.pop_oldest() {
+                Some((key, size)) => {
+                    self.total_size -= size;
+                    evicted.push(key);
+                }
+                None => break,
+            }
+        }
+
+        evicted
+    }
+}
+
+static CACHE: OnceCell<ResizeCache> = OnceCell::new();
+
+impl ResizeCache {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Size of a cached variant, if present.
+    pub fn size_of(&self, key: &str) -> Option<u64> {
+        self.lock().touch(key)
+    }
+
+    /// Read a cached variant.
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.lock().touch(key)?;
+
+        match fs::read(self.path(key)).await {
+            Ok(data) => Some(data),
+            Err(_) => {
+                // Someone removed the file from under us.
+                self.lock().remove(key);
+                None
+            }
+        }
+    }
+
+    /// Store a variant, evicting older variants to make room.
+    pub async fn insert(&self, key: String, data: Vec<u8>) {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return;
+        }
+
+        // Write to a temporary file first so readers never see a partial variant.
+        let path = self.path(&key);
+        let tmp = self.root.join(format!(".{}.tmp", key));
+        if let Err(err) = fs::write(&tmp, data).await {
+            warn!("Failed to write resize cache entry {}: {}", key, err);
+            return;
+        }
+
+        if let Err(err) = fs::rename(&tmp, &path).await {
+            warn!("Failed to write resize cache entry {}: {}", key, err);
+            fs::remove_file(&tmp).await.ok();
+            return;
+        }
+
+        let evicted = {
+            let mut state = self.lock();
+            state.insert(key, size);
+            state.evict(self.max_size)
+        };
+
+        for key in evicted {
+            fs::remove_file(self.path(&key)).await.ok();
+        }
+    }
+
+    /// Remove every cached variant of a file.
+    pub async fn purge(&self, id: &str) {
+        let prefix = format!("{}-", id);
+        let removed: Vec<String> = {
+            let mut state = self.lock();
+            let keys: Vec<String> = state
+                .entries
+                .keys()
+                .filter(|key| key.starts_with(&prefix))
+                .cloned()
+                .collect();
+
+            keys.into_iter().filter(|key| state.remove(key)).collect()
+        };
+
+        for key in removed {
+            fs::remove_file(self.path(&key)).await.ok();
+        }
+    }
+}
+
+/// Set up the resize cache if it is enabled, picking up
+/// any variants left on disk by a previous run.
+pub async fn init() {
+    let max_size = Config::global().cache.max_size;
+    if max_size == 0 {
+        info!("Resize cache is disabled.");
+        return;
+    }
+
+    let root = PathBuf::from(CACHE_PATH.to_string());
+    fs::create_dir_all(&root)
+        .await
+        .expect("Failed to create resize cache directory.");
+
+    // Order existing variants by when they were last written.
+    let mut existing = vec![];
+    let mut entries = fs::read_dir(&root)
+        .await
+        .expect("Failed to read resize cache directory.");
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let key = entry.file_name().to_string_lossy().to_string();
+        if key.starts_with('.') {
+            fs::remove_file(entry.path()).await.ok();
+            continue;
+        }
+
+        if let Ok(metadata) = entry.metadata().await {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+
+            existing.push((modified, key, metadata.len()));
+        }
+    }
+
+    existing.sort();
+
+    let mut state = State::default();
+    for (_, key, size) in existing {
+        state.insert(key, size);
+    }
+
+    for key in state.evict(max_size) {
+        fs::remove_file(root.join(key)).await.ok();
+    }
+
+    info!(
+        "Resize cache ready with {} variants ({} bytes).",
+        state.entries.len(),
+        state.total_size
+    );
+
+    let cache = ResizeCache {
+        root,
+        max_size,
+        state: Mutex::new(state),
+    };
+
+    if CACHE.set(cache).is_err() {
+        panic!("Resize cache was already initialised.");
+    }
+}
+
+/// Get the resize cache, if enabled.
+pub fn get() -> Option<&'static ResizeCache> {
+    CACHE.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut state = State::default();
+        state.insert("a".to_string(), 10);
+        state.insert("b".to_string(), 10);
+        state.insert("c".to_string(), 10);
+        assert_eq!(state.total_size, 30);
+
+        // Using "a" makes "b" the oldest.
+        assert_eq!(state.touch("a"), Some(10));
+        assert_eq!(state.evict(20), vec!["b".to_string()]);
+        assert_eq!(state.evict(0), vec!["c".to_string(), "a".to_string()]);
+        assert_eq!(state.total_size, 0);
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn replaces_existing_entries() {
+        let mut state = State::default();
+        state.insert("a".to_string(), 10);
+        state.insert("a".to_string(), 5);
+
+        assert_eq!(state.total_size, 5);
+        assert_eq!(state.entries.len(), 1);
+        assert_eq!(state.touch("missing"), None);
+    }
+
+    #[tokio::test]
+    async fn purges_every_variant_of_a_file() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = ResizeCache {
+            root: root.path().to_path_buf(),
+            max_size: 100,
+            state: Mutex::new(State::default()),
+        };
+
+        for key in ["abc-100x100", "abc-200x200", "abcd-100x100"] {
+            cache.insert(key.to_string(), vec![0; 10]).await;
+        }
+
+        cache.purge("abc").await;
+
+        assert_eq!(cache.size_of("abc-100x100"), None);
+        assert_eq!(cache.get("abc-200x200").await, None);
+        assert!(!root.path().join("abc-100x100").exists());
+        assert_eq!(cache.get("abcd-100x100").await, Some(vec![0; 10]));
+    }
+
+    #[tokio::test]
+    async fn keeps_within_max_size() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = ResizeCache {
+            root: root.path().to_path_buf(),
+            max_size: 25,
+            state: Mutex::new(State::default()),
+        };
+
+        cache.insert("a".to_string(), vec![0; 10]).await;
+        cache.insert("b".to_string(), vec![0; 10]).await;
+        cache.get("a").await;
+        cache.insert("c".to_string(), vec![0; 10]).await;
+        cache.insert("d".to_string(), vec![0; 30]).await;
+
+        assert_eq!(cache.size_of("a"), Some(10));
+        assert_eq!(cache.size_of("b"), None);
+        assert!(!root.path().join("b").exists());
+        assert_eq!(cache.size_of("c"), Some(10));
+        assert_eq!(cache.size_of("d"), None);
+    }
+}
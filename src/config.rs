@@ -15,10 +15,57 @@ pub enum ContentType {
     Audio,
 }
 
+/// Broad kinds of file, used to decide which uploads are scanned.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Image,
+    Video,
+    Audio,
+    Text,
+    File,
+}
+
 fn default_as_true() -> bool {
     true
 }
 
+/// Limits on how much a single uploader may push into a tag.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Quota {
+    /// Length of the window in seconds.
+    pub window: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// Token bucket allowing bursts of `burst` requests,
+/// refilled at `per_second` requests per second.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Limits for each kind of route, per client.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RateLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Limit>,
+    /// Requests continuing an upload, such as tus chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_part: Option<Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign: Option<Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serve_original: Option<Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serve_resized: Option<Limit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<Limit>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Tag {
     pub max_size: usize,
@@ -30,6 +77,20 @@ pub struct Tag {
     pub serve_if_field_present: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restrict_content_type: Option<ContentType>,
+    /// Reject uploads without a valid session or bot token.
+    #[serde(default)]
+    pub require_auth: bool,
+    /// Only serve files through signed, expiring URLs.
+    #[serde(default)]
+    pub require_signature: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>,
+    /// Overrides the global rate limits for this tag.
+    #[serde(default)]
+    pub rate_limit: RateLimits,
+    /// Overrides which categories of file are scanned for this tag.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan: Option<Vec<Category>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,6 +106,110 @@ pub struct FilterConfig {
     pub content_types: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CacheConfig {
+    /// Maximum total size of cached resized images in bytes, 0 disables the cache.
+    #[serde(default)]
+    pub max_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct GcConfig {
+    /// Seconds between runs.
+    pub interval: u64,
+    /// Files fetched from the database at a time.
+    pub batch_size: i64,
+    /// Files deleted at the same time.
+    pub concurrency: usize,
+    /// Milliseconds to wait between batches.
+    pub batch_delay: u64,
+    /// Attempts at deleting an object from storage before giving up.
+    pub max_attempts: i32,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            interval: 600,
+            batch_size: 100,
+            concurrency: 4,
+            batch_delay: 500,
+            max_attempts: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AuthConfig {
+    /// Verify tokens with this endpoint instead of the database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_url: Option<String>,
+}
+
+/// What to do with an upload when clamd can't be reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanFailurePolicy {
+    /// Accept the file unscanned.
+    Open,
+    /// Reject the upload.
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ClamdConfig {
+    /// Connections kept open to clamd, also the number of concurrent scans.
+    pub pool_size: usize,
+    /// Milliseconds to wait for a connection.
+    pub connect_timeout: u64,
+    /// Seconds to wait for a scan to finish.
+    pub timeout: u64,
+    /// Attempts after the first before giving up on a scan.
+    pub retries: u32,
+    pub on_failure: ScanFailurePolicy,
+    /// Categories of file to scan.
+    pub scan: Vec<Category>,
+}
+
+impl Default for ClamdConfig {
+    fn default() -> Self {
+        ClamdConfig {
+            pool_size: 8,
+            connect_timeout: 2000,
+            timeout: 30,
+            retries: 2,
+            on_failure: ScanFailurePolicy::Closed,
+            scan: vec![
+                Category::Image,
+                Category::Video,
+                Category::Audio,
+                Category::Text,
+                Category::File,
+            ],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct QuarantineConfig {
+    /// Keep files flagged as malware instead of discarding them.
+    pub enabled: bool,
+    /// Storage location samples are kept in, must not be a tag.
+    pub location: String,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        QuarantineConfig {
+            enabled: false,
+            location: "quarantine".to_string(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub tags: HashMap<String, Tag>,
@@ -52,6 +217,18 @@ pub struct Config {
     pub jpeg_quality: u8,
     #[serde(default)]
     pub filter: FilterConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimits,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub clamd: ClamdConfig,
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
 }
 
 static INSTANCE: OnceCell<Config> = OnceCell::new();
@@ -1,14 +1,18 @@
+use crate::cache;
 use crate::config::Tag;
-use crate::util::result::Error;
-use crate::util::variables::{
-    get_s3_bucket, LOCAL_STORAGE_PATH, MONGO_DATABASE, MONGO_URI, USE_S3,
-};
-
-use actix_web::web;
-use mongodb::bson::doc;
-use mongodb::{Client, Collection};
+use crate::storage;
+use crate::util::result::{Error, ResultExt};
+use crate::util::variables::{MONGO_DATABASE, MONGO_URI};
+
+use log::{info, warn};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::{CommandError, ErrorKind, WriteError, WriteFailure};
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateOptions};
+use mongodb::{Client, Collection, IndexModel};
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
 static DBCONN: OnceCell<Client> = OnceCell::new();
 
@@ -20,7 +24,60 @@ pub async fn connect() {
     DBCONN.set(client).unwrap();
 }
 
-pub fn get_collection(collection: &str) -> Collection<File> {
+pub async fn create_indexes() {
+    let attachments = get_collection::<File>("attachments");
+    for keys in [
+        doc! { "uploader_id": 1, "uploaded_at": -1 },
+        doc! { "tag": 1, "uploader_id": 1, "uploaded_at": -1 },
+        doc! { "ip_hash": 1, "uploaded_at": -1 },
+    ] {
+        attachments
+            .create_index(IndexModel::builder().keys(keys).build(), None)
+            .await
+            .expect("Failed to create indexes.");
+    }
+
+    get_collection::<QuarantinedFile>("quarantine")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "quarantined_at": -1 })
+                .build(),
+            None,
+        )
+        .await
+        .expect("Failed to create indexes.");
+
+    // Clean up leases left behind by replicas that went away.
+    get_collection::<Document>("leases")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(Duration::from_secs(0))
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await
+        .expect("Failed to create indexes.");
+}
+
+/// Check the database is reachable.
+pub async fn ping() -> Result<(), Error> {
+    DBCONN
+        .get()
+        .unwrap()
+        .database(&MONGO_DATABASE)
+        .run_command(doc! { "ping": 1 }, None)
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+pub fn get_collection<T>(collection: &str) -> Collection<T> {
     DBCONN
         .get()
         .unwrap()
@@ -48,49 +105,399 @@ pub struct File {
     pub content_type: String,
     pub size: isize,
 
+    /// SHA-256 of the stored contents, files uploaded
+    /// before deduplication are stored under their id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    /// User or bot which uploaded the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<DateTime>,
+    /// Salted hash of the uploader's address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deleted: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reported: Option<bool>,
 }
 
+/// Stored object shared by every file in a tag with the same contents.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Blob {
+    /// Tag and hash of the contents, as `{tag}/{hash}`.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub hash: String,
+    pub size: i64,
+    pub references: i64,
+    /// When references were last added or removed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub updated_at: Option<DateTime>,
+    /// Set while the object is being removed from storage.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub deleting_since: Option<DateTime>,
+}
+
+/// How long a deletion may take before we assume whoever
+/// started it went away, and ignore their claim.
+const BLOB_DELETION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Whether a Mongo error was caused by a duplicate key.
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        &*error.kind,
+        ErrorKind::Command(CommandError { code: 11000, .. })
+            | ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
+}
+
+fn deletion_expired() -> DateTime {
+    DateTime::from_system_time(SystemTime::now() - BLOB_DELETION_TIMEOUT)
+}
+
+/// Add a reference to the blob for some contents,
+/// returning how many files now reference it.
+///
+/// If the object is being deleted, waits for that to finish
+/// so the caller knows to upload the contents again.
+pub async fn acquire_blob(tag: &str, hash: &str, size: i64) -> Result<i64, Error> {
+    let id = format!("{}/{}", tag, hash);
+    let blob = get_collection::<Blob>("blobs")
+        .find_one_and_update(
+            doc! { "_id": &id },
+            doc! {
+                "$setOnInsert": {
+                    "tag": tag,
+                    "hash": hash,
+                    "size": size
+                },
+                "$inc": {
+                    "references": 1_i64
+                },
+                "$set": {
+                    "updated_at": DateTime::now()
+                }
+            },
+            FindOneAndUpdateOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::DatabaseError)?;
+
+    let references = blob.references;
+    let mut deleting_since = blob.deleting_since;
+    while let Some(since) = deleting_since {
+        if since < deletion_expired() {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        deleting_since = get_collection::<Blob>("blobs")
+            .find_one(doc! { "_id": &id }, None)
+            .await
+            .context(Error::DatabaseError)?
+            .and_then(|blob| blob.deleting_since);
+    }
+
+    Ok(references)
+}
+
+/// Store contents under their hash, unless the tag
+/// already has them, and add a reference to them.
+pub async fn store_blob(tag: &str, hash: &str, size: i64, path: &Path) -> Result<(), Error> {
+    let references = acquire_blob(tag, hash, size).await?;
+    if references == 1 || storage::get().head(tag, hash).await.is_err() {
+        if let Err(error) = storage::get().put_file(tag, hash, path).await {
+            release_blob(tag, hash).await.ok();
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a stored object unless some file references it.
+///
+/// While the object is being deleted the blob document is
+/// marked, so anyone acquiring it meanwhile waits for the
+/// deletion to finish before deciding whether to upload it.
+pub async fn delete_unreferenced(tag: &str, key: &str) -> Result<(), Error> {
+    let id = format!("{}/{}", tag, key);
+    let blobs = get_collection::<Blob>("blobs");
+
+    // ? Claim the deletion, creating a placeholder if there's no blob.
+    let claim = blobs
+        .update_one(
+            doc! {
+                "_id": &id,
+                "references": { "$lte": 0_i64 },
+                "$or": [
+                    { "deleting_since": { "$exists": false } },
+                    { "deleting_since": { "$lt": deletion_expired() } }
+                ]
+            },
+            doc! {
+                "$set": { "deleting_since": DateTime::now() },
+                "$setOnInsert": {
+                    "tag": tag,
+                    "hash": key,
+                    "size": 0_i64,
+                    "references": 0_i64
+                }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await;
+
+    match claim {
+        Ok(_) => {}
+        // Referenced again, or someone else is deleting it.
+        Err(error) if is_duplicate_key(&error) => return Ok(()),
+        Err(error) => return Err(error).context(Error::DatabaseError),
+    }
+
+    let result = match storage::get().delete(tag, key).await {
+        Err(Error::NotFound) => Ok(()),
+        result => result,
+    };
+
+    // ? Release the claim, dropping the document if it's still unused.
+    let removed = blobs
+        .delete_one(doc! { "_id": &id, "references": { "$lte": 0_i64 } }, None)
+        .await
+        .context(Error::DatabaseError)?;
+
+    if removed.deleted_count == 0 {
+        blobs
+            .update_one(
+                doc! { "_id": &id },
+                doc! { "$unset": { "deleting_since": "" } },
+                None,
+            )
+            .await
+            .context(Error::DatabaseError)?;
+    }
+
+    result
+}
+
+/// Remove a reference to the blob for some contents,
+/// returning how many files still reference it.
+pub async fn decrement_blob(tag: &str, hash: &str) -> Result<i64, Error> {
+    Ok(get_collection::<Blob>("blobs")
+        .find_one_and_update(
+            doc! { "_id": format!("{}/{}", tag, hash) },
+            doc! {
+                "$inc": {
+                    "references": -1_i64
+                },
+                "$set": {
+                    "updated_at": DateTime::now()
+                }
+            },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context(Error::DatabaseError)?
+        .map(|blob| blob.references)
+        .unwrap_or(0))
+}
+
+/// Remove a reference to the blob for some contents,
+/// deleting the stored object once nothing references it.
+///
+/// Anything which fails is queued to be retried.
+pub async fn release_blob(tag: &str, hash: &str) -> Result<(), Error> {
+    let references = match decrement_blob(tag, hash).await {
+        Ok(references) => references,
+        Err(error) => {
+            record_failed_deletion(tag, hash, true).await?;
+            return Err(error);
+        }
+    };
+
+    if references <= 0 {
+        if let Err(error) = delete_unreferenced(tag, hash).await {
+            record_failed_deletion(tag, hash, false).await?;
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Resumable upload which has not yet been completed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadSession {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub filename: String,
+    /// User or bot which created the upload, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader_id: Option<String>,
+    /// Total size declared by the client.
+    pub length: i64,
+    /// Number of bytes received so far.
+    pub offset: i64,
+    /// Offsets at which each stored part starts, in order.
+    #[serde(default)]
+    pub parts: Vec<i64>,
+    /// Request currently writing to the upload, so only one
+    /// replica at a time can append to it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub claimed_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub claimed_until: Option<DateTime>,
+    pub expires_at: DateTime,
+}
+
+impl UploadSession {
+    /// Storage key of the part starting at `offset`.
+    pub fn part_key(&self, offset: i64) -> String {
+        format!("{}.part{}", self.id, offset)
+    }
+}
+
+/// File id reserved for a client uploading directly to storage.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirectUpload {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader_id: Option<String>,
+    pub expires_at: DateTime,
+}
+
+/// Upload flagged as malware, kept for review.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuarantinedFile {
+    /// Also the key the sample is stored under.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    /// Name of the signature clamd matched.
+    pub signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    pub quarantined_at: DateTime,
+}
+
 impl File {
+    /// Key this file's contents are stored under.
+    pub fn storage_key(&self) -> &str {
+        self.hash.as_deref().unwrap_or(&self.id)
+    }
+
     pub async fn delete_in_storage(&self) -> Result<(), Error> {
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&self.tag)?;
-
-            let (_, code) = bucket
-                .delete_object(format!("/{}", &self.id))
-                .await
-                .map_err(|_| Error::S3Error)?;
-
-            if code != 200 {
-                return Err(Error::S3Error);
-            }
-        } else {
-            let path = format!("{}/{}", *LOCAL_STORAGE_PATH, &self.id);
-            web::block(|| std::fs::remove_file(path))
-                .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
+        if let Some(cache) = cache::get() {
+            cache.purge(&self.id).await;
+        }
+
+        // Other files may still be using the same contents.
+        if let Some(hash) = &self.hash {
+            return release_blob(&self.tag, hash).await;
+        }
+
+        if let Err(error) = storage::get().delete(&self.tag, &self.id).await {
+            record_failed_deletion(&self.tag, &self.id, false).await?;
+            return Err(error);
         }
 
         Ok(())
     }
 
-    pub async fn delete(self) -> Result<(), Error> {
-        self.delete_in_storage().await.ok();
-
-        get_collection("attachments")
+    /// Remove the file, returning whether we were the ones to do so.
+    ///
+    /// If the stored contents can't be removed, the deletion
+    /// is recorded so that it can be retried later.
+    pub async fn delete(self) -> Result<Deletion, Error> {
+        let result = get_collection::<File>("attachments")
             .delete_one(doc! { "_id": &self.id }, None)
             .await
-            .map_err(|_| Error::DatabaseError)?;
+            .context(Error::DatabaseError)?;
 
-        println!("Deleted attachment {}", self.id);
-        Ok(())
+        // Someone else already deleted it.
+        if result.deleted_count == 0 {
+            return Ok(Deletion::AlreadyGone);
+        }
+
+        if let Err(error) = self.delete_in_storage().await {
+            warn!("Failed to delete {} from storage: {}", self.id, error);
+            return Ok(Deletion::StorageFailed);
+        }
+
+        info!("Deleted attachment {}", self.id);
+        Ok(Deletion::Deleted)
     }
 }
 
+/// Outcome of deleting a file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Deletion {
+    Deleted,
+    AlreadyGone,
+    /// The document is gone but the contents are still in storage.
+    StorageFailed,
+}
+
+/// Stored object which could not be deleted and should be retried.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FailedDeletion {
+    /// Tag and storage key, as `{tag}/{key}`.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub tag: String,
+    pub key: String,
+    pub attempts: i32,
+    pub retry_at: DateTime,
+    /// References to the blob which still have to be removed.
+    #[serde(default)]
+    pub pending_releases: i64,
+}
+
+/// Queue a stored object to be deleted later, noting if a
+/// reference to it has to be removed first.
+pub async fn record_failed_deletion(tag: &str, key: &str, release: bool) -> Result<(), Error> {
+    get_collection::<FailedDeletion>("failed_deletions")
+        .update_one(
+            doc! { "_id": format!("{}/{}", tag, key) },
+            doc! {
+                "$setOnInsert": {
+                    "tag": tag,
+                    "key": key,
+                    "attempts": 0_i32,
+                    "retry_at": DateTime::now()
+                },
+                "$inc": {
+                    "pending_releases": if release { 1_i64 } else { 0_i64 }
+                }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
 pub async fn find_file(id: &str, tag: (String, &Tag)) -> Result<File, Error> {
     let mut query = doc! { "_id": id, "tag": tag.0 };
 
@@ -107,9 +514,72 @@ pub async fn find_file(id: &str, tag: (String, &Tag)) -> Result<File, Error> {
         query.insert("$or", or);
     }
 
-    get_collection("attachments")
+    get_collection::<File>("attachments")
         .find_one(query, None)
         .await
-        .map_err(|_| Error::DatabaseError)?
+        .context(Error::DatabaseError)?
         .ok_or(Error::NotFound)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nanoid::nanoid;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    async fn references(tag: &str, hash: &str) -> Option<i64> {
+        get_collection::<Blob>("blobs")
+            .find_one(doc! { "_id": format!("{}/{}", tag, hash) }, None)
+            .await
+            .unwrap()
+            .map(|blob| blob.references)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at AUTUMN_MONGO_URI"]
+    async fn shares_blobs_until_the_last_reference_is_released() {
+        storage::init_memory();
+        connect().await;
+
+        let tag = format!("test-{}", nanoid!(8));
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"contents").unwrap();
+
+        // ? The first upload stores the contents.
+        store_blob(&tag, "hash", 8, file.path()).await.unwrap();
+        assert_eq!(references(&tag, "hash").await, Some(1));
+        assert_eq!(storage::get().get(&tag, "hash").await.unwrap(), b"contents");
+
+        // ? An identical upload only adds a reference.
+        storage::get()
+            .put(&tag, "hash", b"original".to_vec())
+            .await
+            .unwrap();
+
+        store_blob(&tag, "hash", 8, file.path()).await.unwrap();
+        assert_eq!(references(&tag, "hash").await, Some(2));
+        assert_eq!(storage::get().get(&tag, "hash").await.unwrap(), b"original");
+
+        // ? Deleting one file keeps the contents for the other.
+        release_blob(&tag, "hash").await.unwrap();
+        assert_eq!(references(&tag, "hash").await, Some(1));
+        assert!(storage::get().head(&tag, "hash").await.is_ok());
+
+        // ? Deleting the last removes both the blob and the object.
+        release_blob(&tag, "hash").await.unwrap();
+        assert_eq!(references(&tag, "hash").await, None);
+        assert!(matches!(
+            storage::get().head(&tag, "hash").await,
+            Err(Error::NotFound)
+        ));
+
+        // ? Uploading again afterwards stores the contents afresh.
+        store_blob(&tag, "hash", 8, file.path()).await.unwrap();
+        assert_eq!(references(&tag, "hash").await, Some(1));
+        assert_eq!(storage::get().get(&tag, "hash").await.unwrap(), b"contents");
+
+        release_blob(&tag, "hash").await.unwrap();
+    }
+}
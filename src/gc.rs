@@ -0,0 +1,248 @@
+//! Background cleanup of deleted files and expired uploads.
+
+use crate::config::Config;
+use crate::db::{
+    decrement_blob, delete_unreferenced, get_collection, Deletion, FailedDeletion, File,
+};
+use crate::lock;
+use crate::metrics;
+use crate::routes;
+use crate::util::result::{Error, ResultExt};
+
+use futures::{StreamExt, TryStreamExt};
+use log::{error, info, warn};
+use mongodb::bson::{doc, from_document, Bson, DateTime, Document};
+use mongodb::options::FindOptions;
+use std::time::{Duration, SystemTime};
+
+/// How long the lease lasts without being renewed.
+pub const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// Delete one file, keeping track of what happened.
+async fn reclaim(file: File) {
+    let (id, size) = (file.id.clone(), file.size.max(0) as u64);
+
+    match file.delete().await {
+        Ok(Deletion::Deleted) => {
+            metrics::GC_FILES_RECLAIMED.inc();
+            metrics::GC_BYTES_RECLAIMED.inc_by(size);
+        }
+        Ok(Deletion::AlreadyGone) => {}
+        Ok(Deletion::StorageFailed) => {
+            // Counted as reclaimed once the retry goes through.
+            metrics::GC_FAILURES.inc();
+        }
+        Err(error) => {
+            warn!("Failed to delete attachment {}: {}", id, error);
+            metrics::GC_FAILURES.inc();
+        }
+    }
+}
+
+/// Delete files marked as deleted, unless they've been reported.
+async fn collect_deleted() -> Result<(), Error> {
+    let config = &Config::global().gc;
+
+    // Walk through in order of id, so files which can't be
+    // deleted are passed over rather than fetched again.
+    let mut last: Option<Bson> = None;
+    loop {
+        let mut filter = doc! {
+            "deleted": true,
+            "reported": {
+                "$ne": true
+            }
+        };
+
+        if let Some(last) = &last {
+            filter.insert("_id", doc! { "$gt": last });
+        }
+
+        let mut cursor = get_collection::<Document>("attachments")
+            .find(
+                filter,
+                FindOptions::builder()
+                    .sort(doc! { "_id": 1 })
+                    .limit(config.batch_size)
+                    .build(),
+            )
+            .await
+            .context(Error::DatabaseError)?;
+
+        let mut count = 0;
+        let mut batch = vec![];
+        while let Some(document) = cursor.try_next().await.context(Error::DatabaseError)? {
+            count += 1;
+            last = document.get("_id").cloned();
+
+            match from_document::<File>(document) {
+                Ok(file) => batch.push(file),
+                Err(error) => {
+                    warn!("Skipping malformed attachment {:?}: {}", last, error);
+                    metrics::GC_FAILURES.inc();
+                }
+            }
+        }
+
+        futures::stream::iter(batch)
+            .for_each_concurrent(config.concurrency.max(1), reclaim)
+            .await;
+
+        if count < config.batch_size {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(config.batch_delay)).await;
+    }
+}
+
+/// Try once more to delete an object from storage.
+async fn retry(deletion: FailedDeletion) -> Result<(), Error> {
+    let collection = get_collection::<FailedDeletion>("failed_deletions");
+
+    let result = async {
+        // ? Remove references which failed to be removed before.
+        for _ in 0..deletion.pending_releases {
+            decrement_blob(&deletion.tag, &deletion.key).await?;
+            collection
+                .update_one(
+                    doc! { "_id": &deletion.id },
+                    doc! { "$inc": { "pending_releases": -1_i64 } },
+                    None,
+                )
+                .await
+                .context(Error::DatabaseError)?;
+        }
+
+        // ? The same contents may have been uploaded again since.
+        delete_unreferenced(&deletion.tag, &deletion.key).await
+    };
+
+    match result.await {
+        Ok(()) => {
+            metrics::GC_RETRIES_SUCCEEDED.inc();
+
+            // Unless another release failed meanwhile.
+            collection
+                .delete_one(
+                    doc! {
+                        "_id": &deletion.id,
+                        "pending_releases": { "$not": { "$gt": 0_i64 } }
+                    },
+                    None,
+                )
+                .await
+                .context(Error::DatabaseError)?;
+        }
+        Err(error) => {
+            warn!("Retry of deleting {} failed: {}", deletion.id, error);
+            metrics::GC_FAILURES.inc();
+
+            // Back off exponentially, up to a day.
+            let delay = Duration::from_secs(60 << deletion.attempts.clamp(0, 10))
+                .min(Duration::from_secs(86400));
+            collection
+                .update_one(
+                    doc! { "_id": &deletion.id },
+                    doc! {
+                        "$inc": { "attempts": 1_i32 },
+                        "$set": { "retry_at": DateTime::from_system_time(SystemTime::now() + delay) }
+                    },
+                    None,
+                )
+                .await
+                .context(Error::DatabaseError)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Retry storage deletions which failed previously.
+async fn retry_failed() -> Result<(), Error> {
+    let config = &Config::global().gc;
+
+    let batch: Vec<FailedDeletion> = get_collection::<FailedDeletion>("failed_deletions")
+        .find(
+            doc! {
+                "retry_at": { "$lte": DateTime::now() },
+                "attempts": { "$lt": config.max_attempts }
+            },
+            FindOptions::builder().limit(config.batch_size).build(),
+        )
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    futures::stream::iter(batch)
+        .for_each_concurrent(config.concurrency.max(1), |deletion| async move {
+            if let Err(error) = retry(deletion).await {
+                warn!("Failed to retry deletion: {}", error);
+            }
+        })
+        .await;
+
+    Ok(())
+}
+
+/// Run every cleanup task once.
+pub async fn run() {
+    metrics::GC_RUNS.inc();
+
+    if let Err(error) = collect_deleted().await {
+        error!("Failed to collect deleted files: {}", error);
+    }
+
+    if let Err(error) = retry_failed().await {
+        error!("Failed to retry storage deletions: {}", error);
+    }
+
+    routes::tus::purge_expired().await;
+    routes::direct::purge_expired().await;
+
+    info!(
+        "Garbage collection finished, {} files ({} bytes) reclaimed and {} failures so far.",
+        metrics::GC_FILES_RECLAIMED.get(),
+        metrics::GC_BYTES_RECLAIMED.get(),
+        metrics::GC_FAILURES.get(),
+    );
+}
+
+/// Run every cleanup task, unless any replica
+/// already has within the last interval.
+///
+/// Must be called while holding the lease.
+async fn run_if_due(interval: Duration) -> Result<(), Error> {
+    let last_run = lock::last_run("gc").await?;
+    if last_run
+        .and_then(|last_run| last_run.elapsed().ok())
+        .is_some_and(|since| since < interval)
+    {
+        return Ok(());
+    }
+
+    lock::mark_run("gc", SystemTime::now()).await?;
+    run().await;
+    Ok(())
+}
+
+/// Run garbage collection on the configured interval,
+/// on whichever replica holds the lease.
+pub fn spawn() {
+    tokio::spawn(async {
+        let interval = Duration::from_secs(Config::global().gc.interval.max(1));
+        let mut timer = tokio::time::interval(interval);
+
+        loop {
+            timer.tick().await;
+            match lock::with_lease("gc", LEASE_TTL, run_if_due(interval)).await {
+                Ok(Some(Err(error))) | Err(error) => {
+                    error!("Garbage collection did not finish: {}", error)
+                }
+                Ok(_) => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,153 @@
+//! Leases stored in Mongo, so that only one replica
+//! runs each background job at a time.
+//!
+//! A lease expires if its holder stops renewing it,
+//! letting another replica take over.
+
+use crate::db::{get_collection, is_duplicate_key};
+use crate::util::result::{Error, ResultExt};
+
+use log::warn;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::FindOneAndUpdateOptions;
+use nanoid::nanoid;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Identifies this replica as the holder of a lease.
+static HOLDER: Lazy<String> = Lazy::new(|| nanoid!(16));
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lease {
+    /// Name of the job.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub holder: String,
+    pub expires_at: DateTime,
+    /// When the job was last started, if it keeps track.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_run: Option<DateTime>,
+}
+
+/// Take or extend a lease, returning whether we now hold it.
+pub async fn acquire(name: &str, ttl: Duration) -> Result<bool, Error> {
+    let result = get_collection::<Lease>("leases")
+        .find_one_and_update(
+            doc! {
+                "_id": name,
+                "$or": [
+                    { "holder": &*HOLDER },
+                    { "expires_at": { "$lte": DateTime::now() } }
+                ]
+            },
+            doc! {
+                "$set": {
+                    "holder": &*HOLDER,
+                    "expires_at": DateTime::from_system_time(SystemTime::now() + ttl)
+                }
+            },
+            FindOneAndUpdateOptions::builder().upsert(true).build(),
+        )
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        // Someone else holds an unexpired lease, so the upsert collided with it.
+        Err(error) if is_duplicate_key(&error) => Ok(false),
+        Err(error) => Err(error).context(Error::DatabaseError),
+    }
+}
+
+/// Give up a lease if we hold it.
+///
+/// The document is kept, so that `last_run` is remembered.
+pub async fn release(name: &str) -> Result<(), Error> {
+    get_collection::<Lease>("leases")
+        .update_one(
+            doc! { "_id": name, "holder": &*HOLDER },
+            doc! { "$set": { "expires_at": DateTime::now() } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+/// When a job was last started by whoever held its lease.
+pub async fn last_run(name: &str) -> Result<Option<SystemTime>, Error> {
+    Ok(get_collection::<Lease>("leases")
+        .find_one(doc! { "_id": name }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .and_then(|lease| lease.last_run)
+        .map(DateTime::to_system_time))
+}
+
+/// Record that we started a job, while holding its lease.
+pub async fn mark_run(name: &str, started: SystemTime) -> Result<(), Error> {
+    get_collection::<Lease>("leases")
+        .update_one(
+            doc! { "_id": name, "holder": &*HOLDER },
+            doc! { "$set": { "last_run": DateTime::from_system_time(started) } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+/// Run a job only if we can take its lease, renewing
+/// the lease for as long as the job is running.
+///
+/// Returns `None` if another replica holds the lease. If the
+/// lease is lost part way through, the job is cancelled.
+pub async fn with_lease<F, T>(name: &str, ttl: Duration, job: F) -> Result<Option<T>, Error>
+where
+    F: Future<Output = T>,
+{
+    if !acquire(name, ttl).await? {
+        return Ok(None);
+    }
+
+    // Finishes once the lease can no longer be relied upon.
+    let mut renewal = {
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(ttl / 3);
+            timer.tick().await;
+            let mut renewed = Instant::now();
+
+            loop {
+                timer.tick().await;
+                match acquire(&name, ttl).await {
+                    Ok(true) => renewed = Instant::now(),
+                    Ok(false) => {
+                        warn!("Lost the lease for {} to another replica.", name);
+                        break;
+                    }
+                    Err(error) => {
+                        warn!("Failed to renew the lease for {}: {}", name, error);
+                        if renewed.elapsed() >= ttl {
+                            warn!("The lease for {} has expired.", name);
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    };
+
+    tokio::pin!(job);
+    tokio::select! {
+        output = &mut job => {
+            renewal.abort();
+            release(name).await?;
+            Ok(Some(output))
+        }
+        _ = &mut renewal => Err(Error::LeaseLost),
+    }
+}
@@ -1,21 +1,32 @@
+pub mod auth;
+pub mod cache;
 pub mod config;
 pub mod db;
+pub mod gc;
+pub mod lock;
+pub mod metrics;
+pub mod quarantine;
+pub mod quota;
+pub mod rate_limit;
+pub mod reconcile;
 pub mod routes;
+pub mod signature;
+pub mod storage;
 pub mod util;
 pub mod version;
 pub mod virus_scan;
 
-use futures::StreamExt;
-use util::variables::{CONFIG, HOST, LOCAL_STORAGE_PATH, USE_S3};
+use util::variables::{CONFIG, HOST, IP_HASH_SALT, MONGO_URI, TRUSTED_PROXIES};
 
 #[macro_use]
 extern crate lazy_static;
 extern crate tree_magic;
 
 use actix_cors::Cors;
+use actix_web::http::Method;
 use actix_web::{middleware, web, App, HttpServer};
 use log::info;
-use mongodb::bson::doc;
+use rate_limit::{RateLimit, Route};
 use std::env;
 
 pub static CACHE_CONTROL: &str = "public, max-age=604800, must-revalidate";
@@ -39,80 +50,136 @@ async fn main() -> std::io::Result<()> {
 
     info!("Starting Autumn server.");
 
+    // ? Fail now rather than on the first request if settings are missing.
+    lazy_static::initialize(&MONGO_URI);
+    lazy_static::initialize(&IP_HASH_SALT);
+    lazy_static::initialize(&TRUSTED_PROXIES);
+
+    metrics::init();
+    signature::init();
+    quarantine::init();
     virus_scan::init();
 
     db::connect().await;
+    db::create_indexes().await;
+
+    storage::init().await;
+    routes::upload::init().await;
 
-    if !*USE_S3 {
-        info!("Ensuring local storage directory exists.");
-        std::fs::create_dir_all(LOCAL_STORAGE_PATH.to_string()).unwrap();
-    } else {
-        info!("Skipping existence check, make sure your S3 buckets exist!");
+    // ? Run a one-off command instead of the server.
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("reconcile") {
+        let repair = args.iter().any(|arg| arg == "--repair");
+        let consistent = reconcile::run(repair).await;
+        std::process::exit(if consistent { 0 } else { 1 });
     }
 
-    tokio::spawn(async {
-        let mut sched = tokio_cron_scheduler::JobScheduler::new();
-
-        sched
-            .add(
-                tokio_cron_scheduler::Job::new_repeated(
-                    core::time::Duration::from_secs(600),
-                    |_, _| {
-                        tokio::spawn(async {
-                            let col = db::get_collection("attachments");
-                            let mut cursor = col
-                                .find(
-                                    doc! {
-                                        "deleted": true,
-                                        "reported": {
-                                            "$ne": true
-                                        }
-                                    },
-                                    None,
-                                )
-                                .await
-                                .unwrap();
-
-                            while let Some(result) = cursor.next().await {
-                                if let Ok(file) = result {
-                                    file.delete().await.unwrap();
-                                }
-
-                                // Delay before doing the next item in list.
-                                tokio::time::sleep(core::time::Duration::from_millis(50)).await;
-                            }
-                        });
-                    },
-                )
-                .unwrap(),
-            )
-            .unwrap();
+    cache::init().await;
 
-        sched.start().await.unwrap();
-    });
+    gc::spawn();
 
     HttpServer::new(|| {
         App::new()
             .wrap(
                 Cors::default()
                     .allowed_origin_fn(|_, _| true)
-                    .allowed_methods(vec!["GET", "POST"])
-                    .allowed_headers(["X-Session-Token", "X-Bot-Token"])
+                    .allowed_methods(vec!["GET", "HEAD", "POST", "PATCH", "DELETE"])
+                    .allowed_headers([
+                        "X-Session-Token",
+                        "X-Bot-Token",
+                        "Content-Type",
+                        "Tus-Resumable",
+                        "Upload-Length",
+                        "Upload-Offset",
+                        "Upload-Metadata",
+                    ])
+                    .expose_headers([
+                        "Location",
+                        "Tus-Resumable",
+                        "Tus-Version",
+                        "Tus-Extension",
+                        "Tus-Max-Size",
+                        "Upload-Offset",
+                        "Upload-Length",
+                        "Upload-Expires",
+                        "Retry-After",
+                    ])
                     .supports_credentials(),
             )
             .wrap(middleware::Logger::default())
-            .route("/{tag:[^/]*}", web::post().to(routes::upload::post))
-            .route(
-                "/{tag:[^/]*}/download/{filename:.*}",
-                web::get().to(routes::download::get),
+            .service(
+                web::scope("/admin")
+                    .route("/files", web::get().to(routes::admin::list))
+                    .route("/files/{id}", web::get().to(routes::admin::get))
+                    .route("/files/{id}", web::delete().to(routes::admin::delete))
+                    .route("/files/{id}/report", web::post().to(routes::admin::report))
+                    .route(
+                        "/files/{id}/restore",
+                        web::post().to(routes::admin::restore),
+                    )
+                    .route(
+                        "/uploaders/{uploader_id}/files",
+                        web::delete().to(routes::admin::delete_by_uploader),
+                    )
+                    .route("/quarantine", web::get().to(routes::admin::list_quarantine))
+                    .route(
+                        "/quarantine/{id}",
+                        web::delete().to(routes::admin::purge_quarantine),
+                    ),
+            )
+            .route("/metrics", web::get().to(metrics::get))
+            .route("/healthz", web::get().to(routes::health::healthz))
+            .route("/readyz", web::get().to(routes::health::readyz))
+            .service(
+                web::resource("/{tag:[^/]*}")
+                    .wrap(RateLimit(Route::Upload))
+                    .route(web::post().to(routes::upload::post)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/uploads")
+                    .wrap(RateLimit(Route::Upload))
+                    .route(web::post().to(routes::tus::create))
+                    .route(web::method(Method::OPTIONS).to(routes::tus::options)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/uploads/{id:[^/]*}")
+                    .wrap(RateLimit(Route::UploadPart))
+                    .route(web::head().to(routes::tus::head))
+                    .route(web::patch().to(routes::tus::patch))
+                    .route(web::delete().to(routes::tus::delete)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/direct")
+                    .wrap(RateLimit(Route::Upload))
+                    .route(web::post().to(routes::direct::reserve)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/direct/{id:[^/]*}/finalize")
+                    .wrap(RateLimit(Route::UploadPart))
+                    .route(web::post().to(routes::direct::finalize)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}/sign")
+                    .wrap(RateLimit(Route::Sign))
+                    .route(web::post().to(routes::sign::post)),
+            )
+            .service(
+                web::resource("/{tag:[^/]*}/download/{filename:.*}")
+                    .wrap(RateLimit(Route::Download))
+                    .route(web::get().to(routes::download::get))
+                    .route(web::head().to(routes::download::head)),
             )
-            .route(
-                "/{tag:[^/]*}/{filename:[^/]*}",
-                web::get().to(routes::serve::get),
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}")
+                    .wrap(RateLimit(Route::Serve))
+                    .route(web::get().to(routes::serve::get))
+                    .route(web::head().to(routes::serve::head)),
             )
-            .route(
-                "/{tag:[^/]*}/{filename:[^/]*}/{fn:.*}",
-                web::get().to(routes::serve::get),
+            .service(
+                web::resource("/{tag:[^/]*}/{filename:[^/]*}/{fn:.*}")
+                    .wrap(RateLimit(Route::Serve))
+                    .route(web::get().to(routes::serve::get))
+                    .route(web::head().to(routes::serve::head)),
             )
             .route("/", web::get().to(routes::index::get))
     })
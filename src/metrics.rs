@@ -0,0 +1,154 @@
+//! Prometheus metrics, exported on `/metrics`.
+
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    Opts, Registry, TextEncoder,
+};
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Failed to register metric.");
+
+    metric
+}
+
+fn counter(name: &str, help: &str) -> IntCounter {
+    register(IntCounter::new(name, help).unwrap())
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(IntCounterVec::new(Opts::new(name, help), labels).unwrap())
+}
+
+// Uploads
+pub static UPLOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "autumn_uploads_total",
+        "Files uploaded.",
+        &["tag", "content_type"],
+    )
+});
+
+pub static BYTES_STORED: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "autumn_stored_bytes_total",
+        "Bytes of uploaded files, after processing.",
+        &["tag"],
+    )
+});
+
+pub static UPLOAD_STAGE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "autumn_upload_stage_seconds",
+                "Time spent in each stage of processing an upload.",
+            )
+            .buckets(vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+            ]),
+            &["stage"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Start timing a stage of processing an upload.
+pub fn stage(name: &str) -> HistogramTimer {
+    UPLOAD_STAGE_SECONDS
+        .with_label_values(&[name])
+        .start_timer()
+}
+
+// Serving
+pub static RESIZE_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "autumn_resize_seconds",
+            "Time spent resizing images.",
+        ))
+        .unwrap(),
+    )
+});
+
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "autumn_resize_cache_lookups_total",
+        "Lookups in the resized image cache.",
+        &["result"],
+    )
+});
+
+// Storage
+pub static STORAGE_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "autumn_storage_errors_total",
+        "Failed storage backend operations, not counting missing objects.",
+        &["operation"],
+    )
+});
+
+// Garbage collection
+pub static GC_RUNS: Lazy<IntCounter> =
+    Lazy::new(|| counter("autumn_gc_runs_total", "Garbage collection runs."));
+
+pub static GC_FILES_RECLAIMED: Lazy<IntCounter> = Lazy::new(|| {
+    counter(
+        "autumn_gc_files_reclaimed_total",
+        "Deleted files removed by garbage collection.",
+    )
+});
+
+pub static GC_BYTES_RECLAIMED: Lazy<IntCounter> = Lazy::new(|| {
+    counter(
+        "autumn_gc_bytes_reclaimed_total",
+        "Bytes of deleted files removed by garbage collection.",
+    )
+});
+
+pub static GC_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    counter(
+        "autumn_gc_failures_total",
+        "Files or objects garbage collection failed to delete.",
+    )
+});
+
+pub static GC_RETRIES_SUCCEEDED: Lazy<IntCounter> = Lazy::new(|| {
+    counter(
+        "autumn_gc_retries_succeeded_total",
+        "Storage deletions which succeeded on retry.",
+    )
+});
+
+/// Register every metric up front so they are exported before first use.
+pub fn init() {
+    Lazy::force(&UPLOADS);
+    Lazy::force(&BYTES_STORED);
+    Lazy::force(&UPLOAD_STAGE_SECONDS);
+    Lazy::force(&RESIZE_SECONDS);
+    Lazy::force(&CACHE_LOOKUPS);
+    Lazy::force(&STORAGE_ERRORS);
+    Lazy::force(&GC_RUNS);
+    Lazy::force(&GC_FILES_RECLAIMED);
+    Lazy::force(&GC_BYTES_RECLAIMED);
+    Lazy::force(&GC_FAILURES);
+    Lazy::force(&GC_RETRIES_SUCCEEDED);
+}
+
+pub async fn get() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if encoder.encode(&REGISTRY.gather(), &mut buffer).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
@@ -0,0 +1,107 @@
+//! Keeps uploads flagged as malware for review.
+//!
+//! Samples are stored under a location which is not a tag,
+//! so they can never be served, with a record of where
+//! they came from in the `quarantine` collection.
+
+use crate::config::Config;
+use crate::db::{get_collection, QuarantinedFile};
+use crate::routes::upload::Origin;
+use crate::storage;
+use crate::util::result::{Error, ResultExt};
+
+use log::{info, warn};
+use mongodb::bson::{doc, DateTime};
+use nanoid::nanoid;
+use std::path::Path;
+
+/// Make sure quarantined samples can't be served from a tag.
+pub fn init() {
+    let config = Config::global();
+    if config.quarantine.enabled && config.tags.contains_key(&config.quarantine.location) {
+        panic!(
+            "Quarantine location '{}' must not be a tag.",
+            config.quarantine.location
+        );
+    }
+}
+
+/// Details of a file flagged as malware.
+pub struct Sample<'a> {
+    pub tag: &'a str,
+    pub filename: &'a str,
+    pub content_type: &'a str,
+    pub origin: &'a Origin,
+    pub signature: String,
+}
+
+/// Store a flagged file and record it, if quarantine is enabled.
+pub async fn store(sample: Sample<'_>, path: &Path) -> Result<(), Error> {
+    let config = &Config::global().quarantine;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let size = tokio::fs::metadata(path)
+        .await
+        .context(Error::IOError)?
+        .len();
+
+    let id = nanoid!(42);
+    storage::get().put_file(&config.location, &id, path).await?;
+
+    let record = QuarantinedFile {
+        id,
+        tag: sample.tag.to_string(),
+        filename: sample.filename.to_string(),
+        content_type: sample.content_type.to_string(),
+        size: size as i64,
+        signature: sample.signature,
+        uploader_id: sample.origin.uploader_id.clone(),
+        ip_hash: sample.origin.ip_hash.clone(),
+        quarantined_at: DateTime::now(),
+    };
+
+    if let Err(error) = get_collection::<QuarantinedFile>("quarantine")
+        .insert_one(&record, None)
+        .await
+    {
+        // Don't leave an untracked sample behind.
+        storage::get()
+            .delete(&config.location, &record.id)
+            .await
+            .ok();
+        return Err(error).context(Error::DatabaseError);
+    }
+
+    info!(
+        "Quarantined {} uploaded to {}, matching {}.",
+        record.id, record.tag, record.signature
+    );
+
+    Ok(())
+}
+
+/// Remove a quarantined sample and its record.
+pub async fn purge(id: &str) -> Result<(), Error> {
+    let location = &Config::global().quarantine.location;
+
+    let record = get_collection::<QuarantinedFile>("quarantine")
+        .find_one(doc! { "_id": id }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    match storage::get().delete(location, &record.id).await {
+        Ok(()) => {}
+        Err(Error::NotFound) => warn!("Quarantined sample {} was already gone.", record.id),
+        Err(error) => return Err(error),
+    }
+
+    get_collection::<QuarantinedFile>("quarantine")
+        .delete_one(doc! { "_id": &record.id }, None)
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
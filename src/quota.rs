@@ -0,0 +1,87 @@
+use crate::config::Tag;
+use crate::db::get_collection;
+use crate::util::result::{Error, ResultExt};
+
+use futures::StreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use std::time::{Duration, SystemTime};
+
+/// Files and bytes uploaded to a tag within a window.
+async fn usage(tag_id: &str, field: &str, value: &str, window: u64) -> Result<(u64, u64), Error> {
+    let since = DateTime::from_system_time(SystemTime::now() - Duration::from_secs(window));
+
+    let mut cursor = get_collection::<Document>("attachments")
+        .aggregate(
+            [
+                doc! {
+                    "$match": {
+                        "tag": tag_id,
+                        field: value,
+                        "uploaded_at": { "$gte": since }
+                    }
+                },
+                doc! {
+                    "$group": {
+                        "_id": null,
+                        "files": { "$sum": 1_i64 },
+                        "bytes": { "$sum": "$size" }
+                    }
+                },
+            ],
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    match cursor.next().await {
+        Some(result) => {
+            let usage = result.context(Error::DatabaseError)?;
+            let count = |key| {
+                usage
+                    .get(key)
+                    .and_then(|v| v.as_i64().or_else(|| v.as_i32().map(i64::from)))
+                    .unwrap_or(0)
+                    .max(0) as u64
+            };
+
+            Ok((count("files"), count("bytes")))
+        }
+        None => Ok((0, 0)),
+    }
+}
+
+/// Check whether a new file of the given size fits in the tag's quota.
+///
+/// Uploads are attributed to the uploader, falling back to
+/// their address for anonymous uploads.
+pub async fn check(
+    tag_id: &str,
+    tag: &Tag,
+    uploader_id: Option<&str>,
+    ip_hash: Option<&str>,
+    size: u64,
+) -> Result<(), Error> {
+    let quota = match &tag.quota {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let (field, value) = match (uploader_id, ip_hash) {
+        (Some(id), _) => ("uploader_id", id),
+        (None, Some(hash)) => ("ip_hash", hash),
+        (None, None) => return Ok(()),
+    };
+
+    let (files, bytes) = usage(tag_id, field, value, quota.window).await?;
+    let remaining_files = quota.max_files.map(|max| max.saturating_sub(files));
+    let remaining_bytes = quota.max_bytes.map(|max| max.saturating_sub(bytes));
+
+    if remaining_files == Some(0) || remaining_bytes.is_some_and(|remaining| size > remaining) {
+        return Err(Error::QuotaExceeded {
+            remaining_files,
+            remaining_bytes,
+        });
+    }
+
+    Ok(())
+}
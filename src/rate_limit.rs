@@ -0,0 +1,330 @@
+use crate::auth::{client_ip, unverified_token};
+use crate::config::{Config, Limit, RateLimits};
+use crate::routes::serve::Resize;
+use crate::util::lru::Lru;
+use crate::util::result::{Error, ResultExt};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Query;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use once_cell::sync::Lazy;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Buckets kept before the least recently used are dropped.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Kind of route being limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Route {
+    Upload,
+    UploadPart,
+    Sign,
+    Serve,
+    Download,
+}
+
+/// Limit that applies to a request after looking at its parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Upload,
+    UploadPart,
+    Sign,
+    ServeOriginal,
+    ServeResized,
+    Download,
+}
+
+impl Kind {
+    fn limit(self, limits: &RateLimits) -> Option<Limit> {
+        match self {
+            Kind::Upload => limits.upload,
+            Kind::UploadPart => limits.upload_part,
+            Kind::Sign => limits.sign,
+            Kind::ServeOriginal => limits.serve_original,
+            Kind::ServeResized => limits.serve_resized,
+            Kind::Download => limits.download,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how many seconds until one is
+    /// available, if one ever will be.
+    fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), Option<u64>> {
+        self.refill(limit, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.per_second > 0.0 {
+            Err(Some(((1.0 - self.tokens) / limit.per_second).ceil() as u64))
+        } else {
+            Err(None)
+        }
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    /// Hash of the token, so we don't keep tokens around.
+    Token(u64),
+}
+
+type Key = (Kind, String, Client);
+
+/// Buckets for recently seen clients, forgetting the
+/// least recently used once there are too many.
+#[derive(Default)]
+struct Buckets(Lru<Key, Bucket>);
+
+impl Buckets {
+    fn get(&mut self, key: Key, limit: &Limit, now: Instant) -> &mut Bucket {
+        if !self.0.contains_key(&key) {
+            if self.0.len() >= MAX_BUCKETS {
+                self.0.pop_oldest();
+            }
+
+            let bucket = Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            };
+
+            self.0.insert(key.clone(), bucket);
+        }
+
+        self.0.get_mut(&key).expect("Bucket was just inserted.")
+    }
+}
+
+static BUCKETS: Lazy<Mutex<Buckets>> = Lazy::new(|| Mutex::new(Buckets::default()));
+
+/// Limit for a tag, falling back to the global one.
+fn limit_for(kind: Kind, tag: &str) -> Option<Limit> {
+    let config = Config::global();
+    config
+        .tags
+        .get(tag)
+        .and_then(|tag| kind.limit(&tag.rate_limit))
+        .or_else(|| kind.limit(&config.rate_limit))
+}
+
+/// Charge a request to every client it identifies as.
+fn check(kind: Kind, tag: &str, clients: Vec<Client>) -> Result<(), Error> {
+    let limit = match limit_for(kind, tag) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().context(Error::IOError)?;
+
+    for client in clients {
+        buckets
+            .get((kind, tag.to_string(), client), &limit, now)
+            .take(&limit, now)
+            .map_err(|retry_after| Error::RateLimited { retry_after })?;
+    }
+
+    Ok(())
+}
+
+/// Middleware limiting how often each client may hit a route.
+pub struct RateLimit(pub Route);
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            route: self.0,
+            service,
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    route: Route,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let kind = match self.route {
+            Route::Upload => Kind::Upload,
+            Route::UploadPart => Kind::UploadPart,
+            Route::Sign => Kind::Sign,
+            Route::Download => Kind::Download,
+            Route::Serve => match Query::<Resize>::from_query(req.query_string()) {
+                Ok(Query(Resize {
+                    size: None,
+                    width: None,
+                    height: None,
+                    max_side: None,
+                })) => Kind::ServeOriginal,
+                // Invalid parameters are rejected later, charge them as resizes.
+                _ => Kind::ServeResized,
+            },
+        };
+
+        // Tokens aren't checked here, so they're limited on top of
+        // the address rather than instead of it.
+        let mut clients = vec![];
+        if let Some(ip) = client_ip(req.peer_addr(), req.headers()) {
+            clients.push(Client::Ip(ip));
+        }
+
+        if let Some(token) = unverified_token(req.headers()) {
+            let mut hasher = DefaultHasher::new();
+            token.hash(&mut hasher);
+            clients.push(Client::Token(hasher.finish()));
+        }
+
+        let result = check(kind, req.match_info().query("tag"), clients);
+
+        if let Err(error) = result {
+            return Box::pin(async move { Err(error.into()) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::ResponseError;
+    use std::time::Duration;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        per_second: 0.5,
+    };
+
+    fn key(n: u64) -> Key {
+        (Kind::Upload, "attachments".to_string(), Client::Token(n))
+    }
+
+    #[test]
+    fn allows_bursts_then_refills() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: LIMIT.burst as f64,
+            updated: start,
+        };
+
+        assert_eq!(bucket.take(&LIMIT, start), Ok(()));
+        assert_eq!(bucket.take(&LIMIT, start), Ok(()));
+        assert_eq!(bucket.take(&LIMIT, start), Err(Some(2)));
+
+        // Half a token later we only need to wait a second.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(&LIMIT, later), Err(Some(1)));
+        assert_eq!(bucket.take(&LIMIT, later + Duration::from_secs(1)), Ok(()));
+
+        // Never holds more than a burst.
+        let much_later = later + Duration::from_secs(3600);
+        assert_eq!(bucket.take(&LIMIT, much_later), Ok(()));
+        assert_eq!(bucket.take(&LIMIT, much_later), Ok(()));
+        assert!(bucket.take(&LIMIT, much_later).is_err());
+    }
+
+    #[test]
+    fn never_refills_without_a_rate() {
+        let now = Instant::now();
+        let limit = Limit {
+            burst: 1,
+            per_second: 0.0,
+        };
+
+        let mut bucket = Bucket {
+            tokens: 1.0,
+            updated: now,
+        };
+
+        assert_eq!(bucket.take(&limit, now), Ok(()));
+        assert_eq!(bucket.take(&limit, now), Err(None));
+    }
+
+    #[test]
+    fn only_sends_retry_after_when_known() {
+        let response = Error::RateLimited {
+            retry_after: Some(3),
+        }
+        .error_response();
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "3");
+
+        let response = Error::RateLimited { retry_after: None }.error_response();
+        assert!(response.headers().get("Retry-After").is_none());
+    }
+
+    #[test]
+    fn keeps_buckets_per_key() {
+        let now = Instant::now();
+        let mut buckets = Buckets::default();
+
+        for _ in 0..LIMIT.burst {
+            assert!(buckets.get(key(1), &LIMIT, now).take(&LIMIT, now).is_ok());
+        }
+
+        assert!(buckets.get(key(1), &LIMIT, now).take(&LIMIT, now).is_err());
+        assert!(buckets.get(key(2), &LIMIT, now).take(&LIMIT, now).is_ok());
+    }
+
+    #[test]
+    fn forgets_least_recently_used() {
+        let now = Instant::now();
+        let mut buckets = Buckets::default();
+
+        for n in 0..MAX_BUCKETS as u64 {
+            buckets.get(key(n), &LIMIT, now).tokens = 0.0;
+        }
+
+        // Using the first bucket makes the second the oldest.
+        buckets.get(key(0), &LIMIT, now);
+        buckets.get(key(MAX_BUCKETS as u64), &LIMIT, now);
+
+        assert_eq!(buckets.0.len(), MAX_BUCKETS);
+        assert!(!buckets.0.contains_key(&key(1)));
+        assert_eq!(buckets.get(key(0), &LIMIT, now).tokens, 0.0);
+        assert_eq!(buckets.get(key(2), &LIMIT, now).tokens, 0.0);
+    }
+}
@@ -0,0 +1,313 @@
+//! Cross-check storage against the database.
+//!
+//! Run with `autumn reconcile` to report inconsistencies,
+//! or `autumn reconcile --repair` to also fix them. Exits
+//! with a non-zero status if anything was inconsistent.
+//! Repairs hold the garbage collection lease, so the two
+//! never run at once.
+
+use crate::config::Config;
+use crate::db::{
+    delete_unreferenced, get_collection, release_blob, Blob, DirectUpload, File, UploadSession,
+};
+use crate::gc;
+use crate::lock;
+use crate::storage;
+use crate::util::result::{Error, ResultExt};
+
+use futures::TryStreamExt;
+use log::{error, info, warn};
+use mongodb::bson::{doc, DateTime};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// How old an object must be before we consider it orphaned.
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Whether an object may still be waiting for its document.
+fn is_recent(modified: Option<SystemTime>) -> bool {
+    modified
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(true, |age| age < GRACE_PERIOD)
+}
+
+/// Inconsistencies found in a single tag.
+#[derive(Default)]
+struct Report {
+    /// Objects in storage nothing refers to.
+    orphaned_objects: usize,
+    /// Documents whose contents are missing from storage.
+    dangling_documents: usize,
+    /// Blobs whose reference count is wrong.
+    miscounted_blobs: usize,
+}
+
+async fn reconcile_tag(tag: &str, repair: bool) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    // Blobs are read first, so files uploaded while we're reading
+    // are counted rather than released from their blob.
+    let blobs: Vec<Blob> = get_collection::<Blob>("blobs")
+        .find(doc! { "tag": tag }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    let files: Vec<File> = get_collection::<File>("attachments")
+        .find(doc! { "tag": tag }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    let pending: Vec<DirectUpload> = get_collection::<DirectUpload>("direct_uploads")
+        .find(doc! { "tag": tag }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    let sessions: Vec<UploadSession> = get_collection::<UploadSession>("uploads")
+        .find(doc! { "tag": tag }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    // ? Find documents pointing at missing objects.
+    let mut references: HashMap<String, i64> = HashMap::new();
+    for file in &files {
+        match storage::get().head(tag, file.storage_key()).await {
+            Ok(_) => {
+                if let Some(hash) = &file.hash {
+                    *references.entry(hash.clone()).or_default() += 1;
+                }
+            }
+            Err(Error::NotFound) => {
+                warn!("{}/{} is missing from storage.", tag, file.id);
+                report.dangling_documents += 1;
+
+                if repair {
+                    get_collection::<File>("attachments")
+                        .delete_one(doc! { "_id": &file.id }, None)
+                        .await
+                        .context(Error::DatabaseError)?;
+
+                    if let Some(hash) = &file.hash {
+                        release_blob(tag, hash).await?;
+                    }
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    // ? Make sure blobs count the files actually using them.
+    let mut unsettled = HashSet::new();
+    for blob in &blobs {
+        let actual = references.get(&blob.hash).copied().unwrap_or(0);
+        if actual < blob.references
+            && is_recent(
+                blob.updated_at
+                    .map(|updated_at| updated_at.to_system_time()),
+            )
+        {
+            // Its file may not have been inserted yet.
+            unsettled.insert(blob.hash.clone());
+            continue;
+        }
+
+        if actual != blob.references {
+            warn!(
+                "{} has {} references but is used by {} files.",
+                blob.id, blob.references, actual
+            );
+            report.miscounted_blobs += 1;
+
+            if repair {
+                // Leave it alone if the count changed since we read it.
+                let result = get_collection::<Blob>("blobs")
+                    .update_one(
+                        doc! {
+                            "_id": &blob.id,
+                            "references": blob.references,
+                            "updated_at": blob.updated_at
+                        },
+                        doc! {
+                            "$set": {
+                                "references": actual,
+                                "updated_at": DateTime::now()
+                            }
+                        },
+                        None,
+                    )
+                    .await
+                    .context(Error::DatabaseError)?;
+
+                if result.modified_count == 0 {
+                    warn!("{} changed while reconciling, skipping it.", blob.id);
+                } else if actual == 0 {
+                    // Someone may have just acquired it, so it's
+                    // only deleted once it's settled at zero.
+                    info!(
+                        "{} is no longer referenced, leaving it to the next pass.",
+                        blob.id
+                    );
+                    unsettled.insert(blob.hash.clone());
+                }
+            }
+        }
+    }
+
+    // ? Find objects nothing refers to.
+    let mut known: HashSet<String> = files
+        .iter()
+        .map(|file| file.storage_key().to_string())
+        .collect();
+
+    known.extend(references.into_keys());
+    known.extend(unsettled);
+    known.extend(
+        pending
+            .iter()
+            .map(|upload| format!("{}.pending", upload.id)),
+    );
+    known.extend(sessions.iter().flat_map(|session| {
+        session
+            .parts
+            .iter()
+            .map(move |offset| session.part_key(*offset))
+    }));
+
+    for key in storage::get().list(tag).await? {
+        if known.contains(&key) {
+            continue;
+        }
+
+        // Uploads are stored before their document is inserted.
+        let info = storage::get().head(tag, &key).await?;
+        if is_recent(info.last_modified) {
+            continue;
+        }
+
+        warn!("{}/{} is not referenced by any file.", tag, key);
+        report.orphaned_objects += 1;
+
+        if repair {
+            // Skipped if a blob for it has appeared since.
+            delete_unreferenced(tag, &key).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Find objects stored before tags were kept apart that no file refers to.
+async fn reconcile_legacy(repair: bool) -> Result<usize, Error> {
+    let legacy = storage::get().list_legacy().await?;
+    if legacy.is_empty() {
+        return Ok(0);
+    }
+
+    // Any tag may refer to them, so check against every file.
+    let files: Vec<File> = get_collection::<File>("attachments")
+        .find(doc! { "hash": null }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .try_collect()
+        .await
+        .context(Error::DatabaseError)?;
+
+    let known: HashSet<&str> = files.iter().map(File::storage_key).collect();
+
+    let mut orphaned = 0;
+    for key in legacy {
+        if known.contains(key.as_str()) {
+            continue;
+        }
+
+        let info = storage::get().head("", &key).await?;
+        if is_recent(info.last_modified) {
+            continue;
+        }
+
+        warn!("{} is not referenced by any file.", key);
+        orphaned += 1;
+
+        if repair {
+            delete_unreferenced("", &key).await?;
+        }
+    }
+
+    Ok(orphaned)
+}
+
+/// Check every tag and then the untagged objects.
+async fn check_all(repair: bool) -> bool {
+    let mut consistent = true;
+
+    for tag in Config::global().tags.keys() {
+        match reconcile_tag(tag, repair).await {
+            Ok(report) => {
+                info!(
+                    "{}: {} orphaned objects, {} dangling documents, {} miscounted blobs{}",
+                    tag,
+                    report.orphaned_objects,
+                    report.dangling_documents,
+                    report.miscounted_blobs,
+                    if repair { " (repaired)" } else { "" }
+                );
+
+                consistent &= report.orphaned_objects == 0
+                    && report.dangling_documents == 0
+                    && report.miscounted_blobs == 0;
+            }
+            Err(error) => {
+                error!("Failed to reconcile {}: {}", tag, error);
+                consistent = false;
+            }
+        }
+    }
+
+    match reconcile_legacy(repair).await {
+        Ok(orphaned) => {
+            info!(
+                "Untagged: {} orphaned objects{}",
+                orphaned,
+                if repair { " (repaired)" } else { "" }
+            );
+
+            consistent &= orphaned == 0;
+        }
+        Err(error) => {
+            error!("Failed to reconcile untagged objects: {}", error);
+            consistent = false;
+        }
+    }
+
+    consistent
+}
+
+/// Check every tag, returning whether everything was consistent.
+pub async fn run(repair: bool) -> bool {
+    if !repair {
+        return check_all(false).await;
+    }
+
+    match lock::with_lease("gc", gc::LEASE_TTL, check_all(true)).await {
+        Ok(Some(consistent)) => consistent,
+        Ok(None) => {
+            error!("Garbage collection is running elsewhere, try again later.");
+            false
+        }
+        Err(error) => {
+            error!("Failed to repair: {}", error);
+            false
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Moderation endpoints, only available with the admin token.
+
+use crate::auth::require_admin;
+use crate::db::{get_collection, Deletion, File, QuarantinedFile};
+use crate::quarantine;
+use crate::util::result::{Error, ResultExt};
+
+use actix_web::web::{Path, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
+use log::warn;
+use mongodb::bson::{doc, from_document, to_bson, Bson, DateTime, Document};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Most files returned by a single list request.
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    tag: Option<String>,
+    uploader_id: Option<String>,
+    /// RFC 3339 timestamps bounding when the file was uploaded or quarantined.
+    after: Option<String>,
+    before: Option<String>,
+    limit: Option<i64>,
+}
+
+fn parse_date(value: &str) -> Result<DateTime, Error> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|date| DateTime::from_millis(date.timestamp_millis()))
+        .map_err(|_| Error::MissingData)
+}
+
+/// Build a filter from the query, matching times against `date_field`.
+fn list_filter(query: &ListQuery, date_field: &str) -> Result<Document, Error> {
+    let mut filter = Document::new();
+    if let Some(tag) = &query.tag {
+        filter.insert("tag", tag);
+    }
+
+    if let Some(uploader_id) = &query.uploader_id {
+        filter.insert("uploader_id", uploader_id);
+    }
+
+    let mut range = Document::new();
+    if let Some(after) = &query.after {
+        range.insert("$gte", parse_date(after)?);
+    }
+
+    if let Some(before) = &query.before {
+        range.insert("$lt", parse_date(before)?);
+    }
+
+    if !range.is_empty() {
+        filter.insert(date_field, range);
+    }
+
+    Ok(filter)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Value, Error> {
+    Ok(to_bson(value)
+        .context(Error::DatabaseError)?
+        .into_relaxed_extjson())
+}
+
+async fn find(id: &str) -> Result<File, Error> {
+    get_collection::<File>("attachments")
+        .find_one(doc! { "_id": id }, None)
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::NotFound)
+}
+
+pub async fn get(req: HttpRequest, id: Path<String>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+    let file = find(&id).await?;
+    Ok(HttpResponse::Ok().json(to_json(&file)?))
+}
+
+pub async fn list(req: HttpRequest, query: Query<ListQuery>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    let filter = list_filter(&query, "uploaded_at")?;
+    let options = FindOptions::builder()
+        .sort(doc! { "uploaded_at": -1, "_id": -1 })
+        .limit(query.limit.unwrap_or(100).clamp(1, MAX_LIMIT))
+        .build();
+
+    let mut cursor = get_collection::<File>("attachments")
+        .find(filter, options)
+        .await
+        .context(Error::DatabaseError)?;
+
+    let mut files = vec![];
+    while let Some(file) = cursor.next().await {
+        files.push(to_json(&file.context(Error::DatabaseError)?)?);
+    }
+
+    Ok(HttpResponse::Ok().json(files))
+}
+
+pub async fn report(req: HttpRequest, id: Path<String>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    let result = get_collection::<File>("attachments")
+        .update_one(
+            doc! { "_id": id.as_str() },
+            doc! { "$set": { "reported": true } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    if result.matched_count == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn restore(req: HttpRequest, id: Path<String>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    // Only works until the file has been cleaned up.
+    let result = get_collection::<File>("attachments")
+        .update_one(
+            doc! { "_id": id.as_str(), "deleted": true },
+            doc! { "$unset": { "deleted": "" } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    if result.matched_count == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn delete(req: HttpRequest, id: Path<String>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+    find(&id).await?.delete().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn delete_by_uploader(
+    req: HttpRequest,
+    uploader_id: Path<String>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    // Walk through in pages ordered by id, so files which
+    // fail to delete are passed over rather than retried.
+    let (mut deleted, mut failed) = (0, 0);
+    let mut last: Option<Bson> = None;
+    loop {
+        let mut filter = doc! { "uploader_id": uploader_id.as_str() };
+        if let Some(last) = &last {
+            filter.insert("_id", doc! { "$gt": last });
+        }
+
+        let mut cursor = get_collection::<Document>("attachments")
+            .find(
+                filter,
+                FindOptions::builder()
+                    .sort(doc! { "_id": 1 })
+                    .limit(MAX_LIMIT)
+                    .build(),
+            )
+            .await
+            .context(Error::DatabaseError)?;
+
+        let mut count = 0;
+        while let Some(document) = cursor.try_next().await.context(Error::DatabaseError)? {
+            count += 1;
+            last = document.get("_id").cloned();
+
+            let file = match from_document::<File>(document) {
+                Ok(file) => file,
+                Err(error) => {
+                    warn!("Skipping malformed attachment {:?}: {}", last, error);
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            let id = file.id.clone();
+            match file.delete().await {
+                // Contents which failed to delete are retried by the collector.
+                Ok(Deletion::Deleted | Deletion::StorageFailed) => deleted += 1,
+                Ok(Deletion::AlreadyGone) => {}
+                Err(error) => {
+                    warn!("Failed to delete attachment {}: {}", id, error);
+                    failed += 1;
+                }
+            }
+        }
+
+        if count < MAX_LIMIT {
+            break;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted, "failed": failed })))
+}
+
+pub async fn list_quarantine(
+    req: HttpRequest,
+    query: Query<ListQuery>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+
+    let filter = list_filter(&query, "quarantined_at")?;
+    let options = FindOptions::builder()
+        .sort(doc! { "quarantined_at": -1, "_id": -1 })
+        .limit(query.limit.unwrap_or(100).clamp(1, MAX_LIMIT))
+        .build();
+
+    let mut cursor = get_collection::<QuarantinedFile>("quarantine")
+        .find(filter, options)
+        .await
+        .context(Error::DatabaseError)?;
+
+    let mut samples = vec![];
+    while let Some(sample) = cursor.next().await {
+        samples.push(to_json(&sample.context(Error::DatabaseError)?)?);
+    }
+
+    Ok(HttpResponse::Ok().json(samples))
+}
+
+pub async fn purge_quarantine(req: HttpRequest, id: Path<String>) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+    quarantine::purge(&id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,188 @@
+//! Uploads sent straight to the storage backend,
+//! which are processed once the client says they are done.
+
+use crate::auth::{authenticate, authenticate_upload, client_ip_hash};
+use crate::config::get_tag;
+use crate::db::{get_collection, DirectUpload};
+use crate::quota;
+use crate::storage;
+use crate::util::result::{Error, ResultExt};
+
+use super::upload::{generate_id, process, scratch_file, Origin};
+
+use actix_web::web::Json;
+use actix_web::{HttpRequest, HttpResponse};
+use futures::StreamExt;
+use mongodb::bson::{doc, DateTime};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+/// How long the pre-signed URL stays valid, in seconds.
+const PRESIGN_EXPIRY: u32 = 60 * 15;
+
+/// How long the client has to finalize the upload.
+const RESERVATION_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+pub struct Reservation {
+    filename: String,
+    size: u64,
+}
+
+/// Key the object is uploaded to before it has been processed.
+fn staging_key(id: &str) -> String {
+    format!("{}.pending", id)
+}
+
+/// Copy an object from storage into a temporary file.
+async fn spool(tag: &str, key: &str) -> Result<NamedTempFile, Error> {
+    let tmp = scratch_file()?;
+    let mut writer = tokio::fs::File::from_std(tmp.reopen().context(Error::IOError)?);
+
+    let mut object = storage::get().stream(tag, key).await?;
+    while let Some(chunk) = object.body.next().await {
+        writer.write_all(&chunk?).await.context(Error::IOError)?;
+    }
+
+    writer.flush().await.context(Error::IOError)?;
+    Ok(tmp)
+}
+
+/// Discard reservations which were never finalized.
+pub async fn purge_expired() {
+    let mut cursor = match get_collection::<DirectUpload>("direct_uploads")
+        .find(doc! { "expires_at": { "$lte": DateTime::now() } }, None)
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(_) => return,
+    };
+
+    while let Some(Ok(upload)) = cursor.next().await {
+        storage::get()
+            .delete(&upload.tag, &staging_key(&upload.id))
+            .await
+            .ok();
+
+        get_collection::<DirectUpload>("direct_uploads")
+            .delete_one(doc! { "_id": &upload.id }, None)
+            .await
+            .ok();
+    }
+}
+
+/// Reserve a file id and hand out a form to upload it with,
+/// which only accepts a file of exactly the declared size.
+pub async fn reserve(req: HttpRequest, data: Json<Reservation>) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let uploader = authenticate_upload(&req, tag).await?;
+    let Reservation { filename, size } = data.into_inner();
+
+    if size > tag.max_size as u64 {
+        return Err(Error::FileTooLarge {
+            max_size: tag.max_size,
+        });
+    }
+
+    let uploader_id = uploader.map(|uploader| uploader.id);
+    quota::check(
+        &tag_id,
+        tag,
+        uploader_id.as_deref(),
+        client_ip_hash(&req).as_deref(),
+        size,
+    )
+    .await?;
+
+    let id = generate_id(tag);
+    let upload = storage::get().presign_upload(&tag_id, &staging_key(&id), size, PRESIGN_EXPIRY)?;
+
+    get_collection::<DirectUpload>("direct_uploads")
+        .insert_one(
+            DirectUpload {
+                id: id.clone(),
+                tag: tag_id,
+                filename,
+                uploader_id,
+                expires_at: DateTime::from_system_time(SystemTime::now() + RESERVATION_EXPIRY),
+            },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": id,
+        "url": upload.url,
+        "fields": upload.fields,
+        "expires_in": PRESIGN_EXPIRY
+    })))
+}
+
+/// Process an object the client has uploaded and create the file.
+pub async fn finalize(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let uploader_id = authenticate(&req).await?.map(|uploader| uploader.id);
+    let id = req.match_info().query("id");
+
+    let filter = doc! {
+        "_id": id,
+        "tag": &tag_id,
+        "uploader_id": &uploader_id,
+        "expires_at": {
+            "$gt": DateTime::now()
+        }
+    };
+
+    let collection = get_collection::<DirectUpload>("direct_uploads");
+    collection
+        .find_one(filter.clone(), None)
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    // Make sure the client actually uploaded something
+    // before we use up the reservation.
+    let key = staging_key(id);
+    let info = storage::get().head(&tag_id, &key).await?;
+
+    let upload = collection
+        .find_one_and_delete(filter, None)
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    let origin = Origin {
+        uploader_id,
+        ip_hash: client_ip_hash(&req),
+    };
+
+    let result = async {
+        if info.size > tag.max_size as u64 {
+            return Err(Error::FileTooLarge {
+                max_size: tag.max_size,
+            });
+        }
+
+        quota::check(
+            &tag_id,
+            tag,
+            origin.uploader_id.as_deref(),
+            origin.ip_hash.as_deref(),
+            info.size,
+        )
+        .await?;
+
+        spool(&tag_id, &key).await
+    }
+    .await;
+
+    // The staging object is never served, so we're done with it either way.
+    storage::get().delete(&tag_id, &key).await.ok();
+
+    let file_info = process(&tag_id, tag, upload.id, upload.filename, origin, result?).await?;
+    Ok(HttpResponse::Ok().json(json!({ "id": file_info.id })))
+}
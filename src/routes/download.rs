@@ -1,34 +1,57 @@
-use crate::config::{get_tag, Config};
-use crate::db::find_file;
+use crate::storage;
+use crate::util::conditional;
 use crate::util::result::Error;
 
-use super::serve::fetch_file;
+use super::serve::{etag, fetch_file, find_servable_file, headers_for};
 
 use actix_web::{HttpRequest, HttpResponse};
 
 pub async fn get(req: HttpRequest) -> Result<HttpResponse, Error> {
-    let tag = get_tag(&req)?;
+    let (tag, file, cache_control) = find_servable_file(&req).await?;
 
-    let id = req.match_info().query("filename");
-    let file = find_file(id, tag.clone()).await?;
-
-    if let Some(true) = file.deleted {
-        return Err(Error::NotFound);
-    }
-
-    let config = Config::global();
-    if config.filter.content_types.contains(&file.content_type) {
-        return Err(Error::ContentTypeNotAllowed);
+    let etag = etag(&file.id, None);
+    if let Some(response) =
+        conditional::check(&req, &tag, file.storage_key(), &etag, &cache_control).await?
+    {
+        return Ok(response);
     }
 
-    let (contents, _) = fetch_file(id, &tag.0, file.metadata, None).await?;
+    let contents = fetch_file(&file, None).await?;
 
-    Ok(HttpResponse::Ok()
+    let mut builder = HttpResponse::Ok();
+    builder
         .insert_header((
             "Content-Disposition",
             format!("attachment; filename=\"{}\"", file.filename),
         ))
-        .insert_header(("Cache-Control", crate::CACHE_CONTROL))
-        .content_type(file.content_type)
-        .body(contents))
+        .insert_header(("Cache-Control", cache_control));
+
+    contents
+        .respond(&req, builder, file.content_type, etag)
+        .await
+}
+
+pub async fn head(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let (tag, file, cache_control) = find_servable_file(&req).await?;
+
+    let etag = etag(&file.id, None);
+    if let Some(response) =
+        conditional::check(&req, &tag, file.storage_key(), &etag, &cache_control).await?
+    {
+        return Ok(response);
+    }
+
+    let info = storage::get().head(&tag, file.storage_key()).await?;
+
+    Ok(headers_for(
+        HttpResponse::Ok()
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", file.filename),
+            ))
+            .insert_header(("Cache-Control", cache_control)),
+        file.content_type,
+        etag,
+        Some(info),
+    ))
 }
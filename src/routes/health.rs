@@ -0,0 +1,52 @@
+use crate::db;
+use crate::storage;
+use crate::util::variables::USE_CLAMD;
+use crate::virus_scan;
+
+use actix_web::HttpResponse;
+use serde_json::json;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long each dependency has to respond.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness: the process is up and serving requests.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+async fn check<F: Future<Output = bool>>(check: F) -> bool {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or(false)
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "unavailable"
+    }
+}
+
+/// Readiness: every dependency we need to handle requests is reachable.
+pub async fn readyz() -> HttpResponse {
+    let (mongo, storage, clamd) = futures::join!(
+        check(async { db::ping().await.is_ok() }),
+        check(async { storage::get().check().await.is_ok() }),
+        check(async { !*USE_CLAMD || virus_scan::is_available().await }),
+    );
+
+    let body = json!({
+        "mongo": status(mongo),
+        "storage": status(storage),
+        "clamd": if *USE_CLAMD { status(clamd) } else { "disabled" },
+    });
+
+    if mongo && storage && clamd {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
@@ -1,4 +1,9 @@
+pub mod admin;
+pub mod direct;
 pub mod download;
+pub mod health;
 pub mod index;
 pub mod serve;
+pub mod sign;
+pub mod tus;
 pub mod upload;
@@ -1,17 +1,26 @@
+use crate::cache;
 use crate::config::{get_tag, Config, ServeConfig};
 use crate::db::*;
+use crate::metrics;
+use crate::signature;
+use crate::storage::{self, ByteStream, ObjectInfo};
+use crate::util::conditional;
+use crate::util::range::{self, RangeRequest};
 use crate::util::result::Error;
-use crate::util::variables::{get_s3_bucket, LOCAL_STORAGE_PATH, USE_S3};
 
-use actix_web::{web::Query, HttpRequest, HttpResponse};
+use actix_files::HttpRange;
+use actix_web::body::{AnyBody, SizedStream};
+use actix_web::http::header::{self, EntityTag, HttpDate, LastModified};
+use actix_web::http::StatusCode;
+use actix_web::{web::Query, HttpRequest, HttpResponse, HttpResponseBuilder};
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
 use image::{io::Reader as ImageReader, ImageError};
 use mongodb::bson::doc;
 use serde::Deserialize;
 use std::cmp;
 use std::io::Cursor;
-use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use std::time::SystemTime;
 
 #[derive(Deserialize, Debug)]
 pub struct Resize {
@@ -31,7 +40,7 @@ pub fn try_resize(buf: Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>, Imag
         // resize_exact is about 2.5x slower,
         //  thumb approximation doesn't have terrible quality so it's fine to stick with
         //.resize_exact(width as u32, height as u32, image::imageops::FilterType::Gaussian)
-        .thumbnail_exact(width as u32, height as u32);
+        .thumbnail_exact(width, height);
 
     match config.serve {
         ServeConfig::PNG => {
@@ -51,100 +60,303 @@ pub fn try_resize(buf: Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>, Imag
     Ok(bytes)
 }
 
-pub async fn fetch_file(
-    id: &str,
-    tag: &str,
-    metadata: Metadata,
-    resize: Option<Resize>,
-) -> Result<(Vec<u8>, Option<String>), Error> {
-    let mut contents = vec![];
-    let config = Config::global();
+/// Work out the dimensions to resize an image to, if the
+/// request asked for a resize and the file is an image.
+pub fn target_size(metadata: &Metadata, parameters: &Resize) -> Option<(u32, u32)> {
+    if let Metadata::Image { width, height } = *metadata {
+        let shortest_length = cmp::min(width, height);
+        let (target_width, target_height) = match (
+            parameters.size,
+            parameters.max_side,
+            parameters.width,
+            parameters.height,
+        ) {
+            (Some(size), _, _, _) => {
+                let smallest_size = cmp::min(size, shortest_length);
+                (smallest_size, smallest_size)
+            }
+            (_, Some(size), _, _) => {
+                if shortest_length == width {
+                    let h = cmp::min(height, size);
+                    ((width as f32 * (h as f32 / height as f32)) as isize, h)
+                } else {
+                    let w = cmp::min(width, size);
+                    (w, (height as f32 * (w as f32 / width as f32)) as isize)
+                }
+            }
+            (_, _, Some(w), Some(h)) => (cmp::min(width, w), cmp::min(height, h)),
+            (_, _, Some(w), _) => {
+                let w = cmp::min(width, w);
+                (w, (w as f32 * (height as f32 / width as f32)) as isize)
+            }
+            (_, _, _, Some(h)) => {
+                let h = cmp::min(height, h);
+                ((h as f32 * (width as f32 / height as f32)) as isize, h)
+            }
+            _ => return None,
+        };
 
-    if *USE_S3 {
-        let bucket = get_s3_bucket(tag)?;
-        let (data, code) = bucket
-            .get_object(format!("/{}", id))
-            .await
-            .map_err(|_| Error::S3Error)?;
+        Some((target_width as u32, target_height as u32))
+    } else {
+        None
+    }
+}
+
+/// Stable ETag for a file, accounting for any resize and the output format.
+///
+/// File ids are immutable, so the id alone identifies the original contents.
+pub fn etag(id: &str, target: Option<(u32, u32)>) -> EntityTag {
+    match target {
+        Some((width, height)) => {
+            let format = match Config::global().serve {
+                ServeConfig::PNG => "png".to_string(),
+                ServeConfig::WEBP {
+                    quality: Some(quality),
+                } => format!("webp-q{}", quality),
+                ServeConfig::WEBP { quality: None } => "webp-lossless".to_string(),
+            };
 
-        if code != 200 {
-            return Err(Error::S3Error);
+            EntityTag::strong(format!("{}-{}x{}-{}", id, width, height, format))
         }
+        None => EntityTag::strong(id.to_string()),
+    }
+}
 
-        contents = data;
-    } else {
-        let path: PathBuf = format!("{}/{}", *LOCAL_STORAGE_PATH, id)
-            .parse()
-            .map_err(|_| Error::IOError)?;
-
-        let mut f = File::open(path.clone()).await.map_err(|_| Error::IOError)?;
-        f.read_to_end(&mut contents)
-            .await
-            .map_err(|_| Error::IOError)?;
+/// Contents of a file ready to be sent to the client.
+pub enum Contents {
+    /// Original file, streamed straight from storage.
+    Stored { tag: String, id: String },
+    /// File held in memory, with a content type if it was re-encoded.
+    Buffer {
+        contents: Vec<u8>,
+        content_type: Option<String>,
+        last_modified: Option<SystemTime>,
+    },
+}
+
+/// Somewhere we can read ranges of a file from.
+#[derive(Clone)]
+enum Source {
+    Stored { tag: String, id: String },
+    Buffer(Bytes),
+}
+
+impl Source {
+    async fn read(self, range: HttpRange) -> Result<ByteStream, Error> {
+        match self {
+            Source::Stored { tag, id } => {
+                storage::get()
+                    .stream_range(&tag, &id, range.start, range.length)
+                    .await
+            }
+            Source::Buffer(contents) => {
+                let start = range.start as usize;
+                let part = contents.slice(start..start + range.length as usize);
+                Ok(stream::once(async move { Ok(part) }).boxed())
+            }
+        }
+    }
+}
+
+impl Contents {
+    /// Content type to serve, falling back to the original file's.
+    pub fn content_type(&self, original: String) -> String {
+        match self {
+            Contents::Buffer {
+                content_type: Some(content_type),
+                ..
+            } => content_type.clone(),
+            _ => original,
+        }
     }
 
-    if let Some(parameters) = resize {
-        if let Metadata::Image { width, height } = metadata {
-            let shortest_length = cmp::min(width, height);
-            let (target_width, target_height) = match (
-                parameters.size,
-                parameters.max_side,
-                parameters.width,
-                parameters.height,
-            ) {
-                (Some(size), _, _, _) => {
-                    let smallest_size = cmp::min(size, shortest_length);
-                    (smallest_size, smallest_size)
+    /// Finish building a response with these contents,
+    /// only sending the parts asked for by any Range header.
+    pub async fn respond(
+        self,
+        req: &HttpRequest,
+        mut builder: HttpResponseBuilder,
+        content_type: String,
+        etag: EntityTag,
+    ) -> Result<HttpResponse, Error> {
+        builder
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .insert_header(header::ETag(etag.clone()));
+
+        if req.headers().contains_key(header::RANGE) {
+            let (info, source) = match self {
+                Contents::Stored { tag, id } => (
+                    storage::get().head(&tag, &id).await?,
+                    Source::Stored { tag, id },
+                ),
+                Contents::Buffer {
+                    contents,
+                    last_modified,
+                    ..
+                } => (
+                    ObjectInfo {
+                        size: contents.len() as u64,
+                        last_modified,
+                    },
+                    Source::Buffer(contents.into()),
+                ),
+            };
+
+            let size = info.size;
+            if let Some(last_modified) = info.last_modified {
+                builder.insert_header(LastModified(HttpDate::from(last_modified)));
+            }
+
+            let request = if conditional::range_applies(req, &etag, info.last_modified) {
+                range::parse(req, size)
+            } else {
+                RangeRequest::Full
+            };
+
+            return match request {
+                RangeRequest::Full => {
+                    let body = source
+                        .read(HttpRange {
+                            start: 0,
+                            length: size,
+                        })
+                        .await?;
+
+                    Ok(builder
+                        .content_type(content_type)
+                        .body(SizedStream::new(size, body)))
                 }
-                (_, Some(size), _, _) => {
-                    if shortest_length == width {
-                        let h = cmp::min(height, size);
-                        ((width as f32 * (h as f32 / height as f32)) as isize, h)
+                RangeRequest::Unsatisfiable => Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                    .finish()),
+                RangeRequest::Partial(ranges) => {
+                    builder.status(StatusCode::PARTIAL_CONTENT);
+
+                    if let [range] = ranges[..] {
+                        let body = source.read(range).await?;
+
+                        Ok(builder
+                            .insert_header((
+                                header::CONTENT_RANGE,
+                                range::content_range(&range, size),
+                            ))
+                            .content_type(content_type)
+                            .body(SizedStream::new(range.length, body)))
                     } else {
-                        let w = cmp::min(width, size);
-                        (w, (height as f32 * (w as f32 / width as f32)) as isize)
+                        let (boundary, length, body) =
+                            range::multipart(ranges, size, &content_type, move |range| {
+                                source.clone().read(range)
+                            });
+
+                        Ok(builder
+                            .content_type(format!("multipart/byteranges; boundary={}", boundary))
+                            .body(SizedStream::new(length, body)))
                     }
                 }
-                (_, _, Some(w), Some(h)) => (cmp::min(width, w), cmp::min(height, h)),
-                (_, _, Some(w), _) => {
-                    let w = cmp::min(width, w);
-                    (w, (w as f32 * (height as f32 / width as f32)) as isize)
+            };
+        }
+
+        builder.content_type(content_type);
+        Ok(match self {
+            Contents::Stored { tag, id } => {
+                let object = storage::get().stream(&tag, &id).await?;
+                if let Some(last_modified) = object.info.last_modified {
+                    builder.insert_header(LastModified(HttpDate::from(last_modified)));
                 }
-                (_, _, _, Some(h)) => {
-                    let h = cmp::min(height, h);
-                    ((h as f32 * (width as f32 / height as f32)) as isize, h)
+
+                builder.body(SizedStream::new(object.info.size, object.body))
+            }
+            Contents::Buffer {
+                contents,
+                last_modified,
+                ..
+            } => {
+                if let Some(last_modified) = last_modified {
+                    builder.insert_header(LastModified(HttpDate::from(last_modified)));
                 }
-                _ => return Ok((contents, None)),
-            };
 
-            // There should be a way to do this zero-copy, but I can't be asked to figure it out right now.
-            let cloned = contents.clone();
-            if let Ok(Ok(bytes)) = actix_web::web::block(move || {
-                try_resize(cloned, target_width as u32, target_height as u32)
-            })
-            .await
-            {
-                return Ok((
-                    bytes,
-                    Some(
-                        match config.serve {
-                            ServeConfig::PNG => "image/png",
-                            ServeConfig::WEBP { .. } => "image/webp",
-                        }
-                        .to_string(),
-                    ),
-                ));
+                builder.body(contents)
             }
+        })
+    }
+}
+
+pub async fn fetch_file(file: &File, target: Option<(u32, u32)>) -> Result<Contents, Error> {
+    let (tag, key) = (&file.tag, file.storage_key());
+
+    if let Some((target_width, target_height)) = target {
+        let cache_key = etag(&file.id, target).tag().to_string();
+        if let Some(cache) = cache::get() {
+            if let Some(contents) = cache.get(&cache_key).await {
+                metrics::CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
+                return Ok(Contents::Buffer {
+                    contents,
+                    content_type: Some(resized_content_type().to_string()),
+                    last_modified: None,
+                });
+            }
+
+            metrics::CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+        }
+
+        let object = storage::get().stream(tag, key).await?;
+        let last_modified = object.info.last_modified;
+        let contents = object
+            .body
+            .try_fold(
+                Vec::with_capacity(object.info.size as usize),
+                |mut contents, chunk| async move {
+                    contents.extend_from_slice(&chunk);
+                    Ok(contents)
+                },
+            )
+            .await?;
+
+        // There should be a way to do this zero-copy, but I can't be asked to figure it out right now.
+        let cloned = contents.clone();
+        let timer = metrics::RESIZE_SECONDS.start_timer();
+        let resized =
+            actix_web::web::block(move || try_resize(cloned, target_width, target_height)).await;
+        timer.observe_duration();
+
+        if let Ok(Ok(bytes)) = resized {
+            if let Some(cache) = cache::get() {
+                cache.insert(cache_key, bytes.clone()).await;
+            }
+
+            return Ok(Contents::Buffer {
+                contents: bytes,
+                content_type: Some(resized_content_type().to_string()),
+                last_modified,
+            });
         }
+
+        return Ok(Contents::Buffer {
+            contents,
+            content_type: None,
+            last_modified,
+        });
     }
 
-    Ok((contents, None))
+    Ok(Contents::Stored {
+        tag: tag.to_string(),
+        id: key.to_string(),
+    })
 }
 
-pub async fn get(req: HttpRequest, resize: Query<Resize>) -> Result<HttpResponse, Error> {
-    let tag = get_tag(&req)?;
+/// Find a file that may be served from the requested tag.
+/// Find the file a request is for, along with
+/// the Cache-Control to send it with.
+pub async fn find_servable_file(req: &HttpRequest) -> Result<(String, File, String), Error> {
+    let tag = get_tag(req)?;
 
     let id = req.match_info().query("filename");
+    let cache_control = if tag.1.require_signature {
+        signature::cache_control(signature::verify(req, &tag.0, id)?)
+    } else {
+        crate::CACHE_CONTROL.to_string()
+    };
+
     let file = find_file(id, tag.clone()).await?;
 
     if let Some(true) = file.deleted {
@@ -156,20 +368,110 @@ pub async fn get(req: HttpRequest, resize: Query<Resize>) -> Result<HttpResponse
         return Err(Error::ContentTypeNotAllowed);
     }
 
-    let (contents, content_type) = fetch_file(id, &tag.0, file.metadata, Some(resize.0)).await?;
-    let content_type = content_type.unwrap_or(file.content_type);
+    Ok((tag.0, file, cache_control))
+}
 
+/// Build the headers shared by every response for a stored file.
+pub fn headers_for(
+    builder: &mut HttpResponseBuilder,
+    content_type: String,
+    etag: EntityTag,
+    info: Option<ObjectInfo>,
+) -> HttpResponse {
+    builder
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::ETag(etag))
+        .content_type(content_type);
+
+    match info {
+        Some(info) => {
+            if let Some(last_modified) = info.last_modified {
+                builder.insert_header(LastModified(HttpDate::from(last_modified)));
+            }
+
+            builder.body(SizedStream::new(
+                info.size,
+                stream::empty::<Result<Bytes, Error>>(),
+            ))
+        }
+        None => builder.body(AnyBody::None),
+    }
+}
+
+/// Content type that resized images are served as.
+fn resized_content_type() -> &'static str {
+    match Config::global().serve {
+        ServeConfig::PNG => "image/png",
+        ServeConfig::WEBP { .. } => "image/webp",
+    }
+}
+
+/// Whether a file of this type should be displayed inline by browsers.
+fn disposition(content_type: &str) -> &'static str {
     // This list should match files accepted
     // by upload.rs#L68 as allowed images / videos.
-    let diposition = match content_type.as_ref() {
+    match content_type {
         "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "video/mp4" | "video/webm"
         | "video/webp" | "audio/quicktime" | "audio/mpeg" => "inline",
         _ => "attachment",
+    }
+}
+
+pub async fn get(req: HttpRequest, resize: Query<Resize>) -> Result<HttpResponse, Error> {
+    let (tag, file, cache_control) = find_servable_file(&req).await?;
+
+    let target = target_size(&file.metadata, &resize);
+    let etag = etag(&file.id, target);
+    if let Some(response) =
+        conditional::check(&req, &tag, file.storage_key(), &etag, &cache_control).await?
+    {
+        return Ok(response);
+    }
+
+    let contents = fetch_file(&file, target).await?;
+    let content_type = contents.content_type(file.content_type);
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Disposition", disposition(&content_type)))
+        .insert_header(("Cache-Control", cache_control));
+
+    contents.respond(&req, builder, content_type, etag).await
+}
+
+pub async fn head(req: HttpRequest, resize: Query<Resize>) -> Result<HttpResponse, Error> {
+    let (tag, file, cache_control) = find_servable_file(&req).await?;
+
+    let target = target_size(&file.metadata, &resize);
+    let etag = etag(&file.id, target);
+    if let Some(response) =
+        conditional::check(&req, &tag, file.storage_key(), &etag, &cache_control).await?
+    {
+        return Ok(response);
+    }
+
+    // We can't know the size of a resized image without resizing it, which
+    // is too much work for a HEAD request, unless we happen to have it cached.
+    let (content_type, info) = if target.is_some() {
+        let info = cache::get()
+            .and_then(|cache| cache.size_of(etag.tag()))
+            .map(|size| ObjectInfo {
+                size,
+                last_modified: None,
+            });
+
+        (resized_content_type().to_string(), info)
+    } else {
+        let info = storage::get().head(&tag, file.storage_key()).await?;
+        (file.content_type, Some(info))
     };
 
-    Ok(HttpResponse::Ok()
-        .insert_header(("Content-Disposition", diposition))
-        .insert_header(("Cache-Control", crate::CACHE_CONTROL))
-        .content_type(content_type)
-        .body(contents))
+    Ok(headers_for(
+        HttpResponse::Ok()
+            .insert_header(("Content-Disposition", disposition(&content_type)))
+            .insert_header(("Cache-Control", cache_control)),
+        content_type,
+        etag,
+        info,
+    ))
 }
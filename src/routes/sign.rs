@@ -0,0 +1,36 @@
+use crate::auth::require_admin;
+use crate::config::get_tag;
+use crate::signature;
+use crate::util::result::Error;
+
+use super::serve::Resize;
+
+use actix_web::web::Query;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+/// Longest a minted URL may stay valid for.
+const MAX_TTL: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Deserialize)]
+pub struct Options {
+    /// Seconds until the URL expires.
+    ttl: Option<u64>,
+}
+
+pub async fn post(
+    req: HttpRequest,
+    resize: Query<Resize>,
+    options: Query<Options>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&req)?;
+    let (tag, _) = get_tag(&req)?;
+    let id = req.match_info().query("filename");
+
+    let ttl = Duration::from_secs(options.ttl.unwrap_or(3600).min(MAX_TTL));
+    let query = signature::sign(&tag, id, &resize, ttl)?;
+
+    Ok(HttpResponse::Ok().json(json!({ "url": format!("/{}/{}?{}", tag, id, query) })))
+}
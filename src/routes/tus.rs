@@ -0,0 +1,513 @@
+//! Resumable uploads following the tus 1.0.0 protocol.
+//!
+//! See https://tus.io/protocols/resumable-upload.html
+//!
+//! Each PATCH is stored as a separate part in the storage
+//! backend, so any replica can continue an upload. Parts
+//! are joined together once the upload is complete.
+
+use crate::auth::{authenticate, authenticate_upload, client_ip_hash};
+use crate::config::get_tag;
+use crate::db::{get_collection, UploadSession};
+use crate::quota;
+use crate::storage;
+use crate::util::result::{Error, ResultExt};
+
+use super::upload::{generate_id, process, scratch_file, Origin};
+
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use futures::{StreamExt, TryStreamExt};
+use log::warn;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use nanoid::nanoid;
+use serde_json::json;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+static TUS_VERSION: &str = "1.0.0";
+static TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+/// How long an upload may sit idle before it is discarded.
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long a request may hold an upload without renewing its claim.
+const CLAIM_TTL: Duration = Duration::from_secs(5 * 60);
+
+fn claim_expiry() -> DateTime {
+    DateTime::from_system_time(SystemTime::now() + CLAIM_TTL)
+}
+
+/// Take exclusive use of an upload, returning the
+/// claim token and the upload as it is now.
+async fn claim(id: &str) -> Result<(String, UploadSession), Error> {
+    let token = nanoid!(16);
+    let session = get_collection::<UploadSession>("uploads")
+        .find_one_and_update(
+            doc! {
+                "_id": id,
+                "$or": [
+                    { "claimed_until": { "$exists": false } },
+                    { "claimed_until": { "$lt": DateTime::now() } }
+                ]
+            },
+            doc! {
+                "$set": {
+                    "claimed_by": &token,
+                    "claimed_until": claim_expiry()
+                }
+            },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::UploadConflict)?;
+
+    Ok((token, session))
+}
+
+/// Extend a claim, returning whether we still hold it.
+async fn renew(id: &str, token: &str) -> Result<bool, Error> {
+    let result = get_collection::<UploadSession>("uploads")
+        .update_one(
+            doc! { "_id": id, "claimed_by": token },
+            doc! { "$set": { "claimed_until": claim_expiry() } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(result.matched_count > 0)
+}
+
+async fn release(id: &str, token: &str) -> Result<(), Error> {
+    get_collection::<UploadSession>("uploads")
+        .update_one(
+            doc! { "_id": id, "claimed_by": token },
+            doc! { "$unset": { "claimed_by": "", "claimed_until": "" } },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+fn expiry() -> DateTime {
+    DateTime::from_system_time(SystemTime::now() + UPLOAD_EXPIRY)
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), Error> {
+    if header(req, "Tus-Resumable") == Some(TUS_VERSION) {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedVersion)
+    }
+}
+
+/// Find the filename in an `Upload-Metadata` header,
+/// a list of keys and base64 encoded values.
+fn parse_filename(metadata: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != "filename" {
+            return None;
+        }
+
+        let value = base64::decode(parts.next()?).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+/// Find an upload belonging to whoever is making the request.
+async fn find_session(req: &HttpRequest, tag: &str, id: &str) -> Result<UploadSession, Error> {
+    let uploader_id = authenticate(req).await?.map(|uploader| uploader.id);
+
+    let session = get_collection::<UploadSession>("uploads")
+        .find_one(
+            doc! {
+                "_id": id,
+                "tag": tag,
+                "expires_at": {
+                    "$gt": DateTime::now()
+                }
+            },
+            None,
+        )
+        .await
+        .context(Error::DatabaseError)?
+        .ok_or(Error::NotFound)?;
+
+    if session.uploader_id != uploader_id {
+        return Err(Error::NotFound);
+    }
+
+    Ok(session)
+}
+
+async fn remove_session(session: &UploadSession) -> Result<(), Error> {
+    for offset in &session.parts {
+        let key = session.part_key(*offset);
+        if let Err(error) = storage::get().delete(&session.tag, &key).await {
+            warn!("Failed to delete upload part {}: {}", key, error);
+        }
+    }
+
+    get_collection::<UploadSession>("uploads")
+        .delete_one(doc! { "_id": &session.id }, None)
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(())
+}
+
+/// Join the stored parts of an upload into a single file.
+async fn assemble(session: &UploadSession) -> Result<NamedTempFile, Error> {
+    let tmp = scratch_file()?;
+    let mut writer = tokio::fs::File::from_std(tmp.reopen().context(Error::IOError)?);
+
+    for offset in &session.parts {
+        let mut body = storage::get()
+            .stream(&session.tag, &session.part_key(*offset))
+            .await?
+            .body;
+
+        while let Some(chunk) = body.try_next().await? {
+            writer.write_all(&chunk).await.context(Error::IOError)?;
+        }
+    }
+
+    writer.flush().await.context(Error::IOError)?;
+    Ok(tmp)
+}
+
+/// Run a complete upload through the upload pipeline.
+///
+/// The upload is kept if this fails for a reason which might
+/// go away, so the client can try again by sending an empty
+/// PATCH at the final offset.
+async fn complete(req: &HttpRequest, session: UploadSession) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(req)?;
+    let origin = Origin {
+        uploader_id: session.uploader_id.clone(),
+        ip_hash: client_ip_hash(req),
+    };
+
+    let result = async {
+        let file = assemble(&session).await?;
+
+        // Other uploads may have finished since this one was created.
+        quota::check(
+            &tag_id,
+            tag,
+            origin.uploader_id.as_deref(),
+            origin.ip_hash.as_deref(),
+            session.length as u64,
+        )
+        .await?;
+
+        process(
+            &tag_id,
+            tag,
+            generate_id(tag),
+            session.filename.clone(),
+            origin,
+            file,
+        )
+        .await
+    }
+    .await;
+
+    match result {
+        Err(error) if error.status_code().is_server_error() => Err(error),
+        result => {
+            remove_session(&session).await?;
+            let file_info = result?;
+
+            Ok(HttpResponse::Ok()
+                .insert_header(("Tus-Resumable", TUS_VERSION))
+                .insert_header(("Upload-Offset", session.length.to_string()))
+                .json(json!({ "id": file_info.id })))
+        }
+    }
+}
+
+/// Discard uploads which have expired.
+pub async fn purge_expired() {
+    let mut cursor = match get_collection::<UploadSession>("uploads")
+        .find(doc! { "expires_at": { "$lte": DateTime::now() } }, None)
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(_) => return,
+    };
+
+    while let Some(Ok(session)) = cursor.next().await {
+        remove_session(&session).await.ok();
+    }
+}
+
+pub async fn options(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let (_, tag) = get_tag(&req)?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", tag.max_size.to_string()))
+        .finish())
+}
+
+pub async fn create(req: HttpRequest) -> Result<HttpResponse, Error> {
+    check_version(&req)?;
+    let (tag_id, tag) = get_tag(&req)?;
+    let uploader = authenticate_upload(&req, tag).await?;
+
+    let length: usize = header(&req, "Upload-Length")
+        .and_then(|v| v.parse().ok())
+        .ok_or(Error::MissingData)?;
+
+    if length > tag.max_size {
+        return Err(Error::FileTooLarge {
+            max_size: tag.max_size,
+        });
+    }
+
+    let filename = header(&req, "Upload-Metadata")
+        .and_then(parse_filename)
+        .ok_or(Error::MissingData)?;
+
+    let uploader_id = uploader.map(|uploader| uploader.id);
+    quota::check(
+        &tag_id,
+        tag,
+        uploader_id.as_deref(),
+        client_ip_hash(&req).as_deref(),
+        length as u64,
+    )
+    .await?;
+
+    let session = UploadSession {
+        id: nanoid!(42),
+        tag: tag_id.clone(),
+        filename,
+        uploader_id,
+        length: length as i64,
+        offset: 0,
+        parts: vec![],
+        claimed_by: None,
+        claimed_until: None,
+        expires_at: expiry(),
+    };
+
+    get_collection::<UploadSession>("uploads")
+        .insert_one(&session, None)
+        .await
+        .context(Error::DatabaseError)?;
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Location", format!("/{}/uploads/{}", tag_id, session.id)))
+        .insert_header((
+            "Upload-Expires",
+            actix_web::http::header::HttpDate::from(session.expires_at.to_system_time())
+                .to_string(),
+        ))
+        .finish())
+}
+
+pub async fn head(req: HttpRequest) -> Result<HttpResponse, Error> {
+    check_version(&req)?;
+    let (tag_id, _) = get_tag(&req)?;
+    let session = find_session(&req, &tag_id, req.match_info().query("id")).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", session.offset.to_string()))
+        .insert_header(("Upload-Length", session.length.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+pub async fn patch(req: HttpRequest, mut payload: web::Payload) -> Result<HttpResponse, Error> {
+    check_version(&req)?;
+    let (tag_id, _) = get_tag(&req)?;
+
+    if header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return Err(Error::UnsupportedMediaType);
+    }
+
+    let offset: i64 = header(&req, "Upload-Offset")
+        .and_then(|v| v.parse().ok())
+        .ok_or(Error::MissingData)?;
+
+    let id = req.match_info().query("id");
+    find_session(&req, &tag_id, id).await?;
+
+    let (token, session) = claim(id).await?;
+    let result = append(&req, &mut payload, offset, &token, session).await;
+    release(id, &token).await?;
+    result
+}
+
+/// Store the body of a PATCH as the next part of an upload.
+async fn append(
+    req: &HttpRequest,
+    payload: &mut web::Payload,
+    offset: i64,
+    token: &str,
+    mut session: UploadSession,
+) -> Result<HttpResponse, Error> {
+    let (_, tag) = get_tag(req)?;
+
+    if offset != session.offset {
+        return Err(Error::UploadConflict);
+    }
+
+    // Keep whatever arrives even if the connection drops,
+    // so the client can resume from there.
+    let part = scratch_file()?;
+    let mut writer = tokio::fs::File::from_std(part.reopen().context(Error::IOError)?);
+    let mut renewed = Instant::now();
+
+    let mut received = offset;
+    let mut result = Ok(());
+    while let Some(chunk) = payload.next().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(_) => {
+                result = Err(Error::FailedToReceive);
+                break;
+            }
+        };
+
+        if received + data.len() as i64 > session.length {
+            result = Err(Error::FileTooLarge {
+                max_size: tag.max_size,
+            });
+            break;
+        }
+
+        if writer.write_all(&data).await.is_err() {
+            result = Err(Error::IOError);
+            break;
+        }
+
+        received += data.len() as i64;
+
+        if renewed.elapsed() > CLAIM_TTL / 3 {
+            if !renew(&session.id, token).await? {
+                return Err(Error::UploadConflict);
+            }
+
+            renewed = Instant::now();
+        }
+    }
+
+    writer.flush().await.context(Error::IOError)?;
+    drop(writer);
+
+    if received > offset {
+        storage::get()
+            .put_file(&session.tag, &session.part_key(offset), part.path())
+            .await?;
+
+        let updated = get_collection::<UploadSession>("uploads")
+            .update_one(
+                doc! { "_id": &session.id, "claimed_by": token, "offset": offset },
+                doc! {
+                    "$set": {
+                        "offset": received,
+                        "expires_at": expiry()
+                    },
+                    "$push": {
+                        "parts": offset
+                    }
+                },
+                None,
+            )
+            .await
+            .context(Error::DatabaseError)?;
+
+        if updated.matched_count == 0 {
+            storage::get()
+                .delete(&session.tag, &session.part_key(offset))
+                .await
+                .ok();
+
+            return Err(Error::UploadConflict);
+        }
+
+        session.offset = received;
+        session.parts.push(offset);
+    }
+
+    result?;
+
+    if received < session.length {
+        return Ok(HttpResponse::NoContent()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header(("Upload-Offset", received.to_string()))
+            .finish());
+    }
+
+    // ? Upload is complete, hand it over to the regular pipeline.
+    complete(req, session).await
+}
+
+pub async fn delete(req: HttpRequest) -> Result<HttpResponse, Error> {
+    check_version(&req)?;
+    let (tag_id, _) = get_tag(&req)?;
+
+    let id = req.match_info().query("id");
+    find_session(&req, &tag_id, id).await?;
+
+    let (_, session) = claim(id).await?;
+    remove_session(&session).await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_filename_in_metadata() {
+        let metadata = format!(
+            "filetype {},filename {}, is_confidential",
+            base64::encode("image/png"),
+            base64::encode("cat picture.png")
+        );
+
+        assert_eq!(
+            parse_filename(&metadata),
+            Some("cat picture.png".to_string())
+        );
+    }
+
+    #[test]
+    fn ignores_missing_or_invalid_filenames() {
+        assert_eq!(parse_filename(""), None);
+        assert_eq!(parse_filename("filetype aW1hZ2UvcG5n"), None);
+        assert_eq!(parse_filename("filename"), None);
+        assert_eq!(parse_filename("filename not-base64!"), None);
+        assert_eq!(
+            parse_filename(&format!("filename {}", base64::encode([0xff, 0xfe]))),
+            None
+        );
+        assert_eq!(
+            parse_filename(&format!("filenames {}", base64::encode("a.png"))),
+            None
+        );
+    }
+}
@@ -1,24 +1,47 @@
-use crate::config::{get_tag, Config, ContentType};
+use crate::auth::{authenticate_upload, client_ip_hash};
+use crate::config::{get_tag, Category, Config, ContentType, Tag};
 use crate::db::*;
-use crate::util::result::Error;
-use crate::util::variables::{get_s3_bucket, CLAMD_HOST, LOCAL_STORAGE_PATH, USE_CLAMD, USE_S3};
-
-use actix_multipart::Multipart;
+use crate::metrics;
+use crate::quarantine::{self, Sample};
+use crate::quota;
+use crate::util::result::{Error, ResultExt};
+use crate::util::variables::UPLOADS_PATH;
+use crate::virus_scan::{self, Verdict};
+
+use actix_multipart::{Field, Multipart};
 use actix_web::{web, HttpRequest, HttpResponse};
 use content_inspector::inspect;
 use ffprobe::ffprobe;
 use futures::{StreamExt, TryStreamExt};
 use image::io::Reader as ImageReader;
 use imagesize;
+use log::error;
+use mongodb::bson::DateTime;
 use nanoid::nanoid;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::convert::TryInto;
-use std::io::{Cursor, Read, Write};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
 use std::process::Command;
 use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+/// Make sure the directory uploads are received into exists.
+pub async fn init() {
+    tokio::fs::create_dir_all(&*UPLOADS_PATH)
+        .await
+        .expect("Failed to create uploads directory.");
+}
+
+/// Create a temporary file in the uploads directory.
+pub fn scratch_file() -> Result<NamedTempFile, Error> {
+    NamedTempFile::new_in(&*UPLOADS_PATH).context(Error::IOError)
+}
 
 pub fn determine_video_size(path: &std::path::Path) -> Result<(isize, isize), Error> {
-    let data = ffprobe(path).map_err(|_| Error::ProbeError)?;
+    let data = ffprobe(path).context(Error::ProbeError)?;
 
     // Take the first valid stream.
     for stream in data.streams {
@@ -32,275 +55,395 @@ pub fn determine_video_size(path: &std::path::Path) -> Result<(isize, isize), Er
     Err(Error::ProbeError)
 }
 
-pub async fn post(req: HttpRequest, mut payload: Multipart) -> Result<HttpResponse, Error> {
-    let config = Config::global();
-    let (tag_id, tag) = get_tag(&req)?;
-
-    if let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().ok_or(Error::FailedToReceive)?;
-        let filename = content_type
-            .get_filename()
-            .ok_or(Error::FailedToReceive)?
-            .to_string();
+/// Spool a multipart field into a temporary file,
+/// enforcing the size limit as chunks arrive.
+pub async fn receive_field(field: &mut Field, max_size: usize) -> Result<NamedTempFile, Error> {
+    let tmp = scratch_file()?;
+    let mut writer = tokio::fs::File::from_std(tmp.reopen().context(Error::IOError)?);
 
-        // ? Read multipart data into a buffer.
-        let mut file_size: usize = 0;
-        let mut buf: Vec<u8> = Vec::new();
-        while let Some(chunk) = field.next().await {
-            let data = chunk.map_err(|_| Error::FailedToReceive)?;
-            file_size += data.len();
-
-            if file_size > tag.max_size {
-                return Err(Error::FileTooLarge {
-                    max_size: tag.max_size,
-                });
-            }
+    let mut file_size: usize = 0;
+    while let Some(chunk) = field.next().await {
+        let data = chunk.context(Error::FailedToReceive)?;
+        file_size += data.len();
 
-            buf.append(&mut data.to_vec());
+        if file_size > max_size {
+            return Err(Error::FileTooLarge { max_size });
         }
 
-        // ? Find the content-type of the data.
-        let mut content_type = tree_magic::from_u8(&buf);
+        writer.write_all(&data).await.context(Error::IOError)?;
+    }
+
+    writer.flush().await.context(Error::IOError)?;
+    Ok(tmp)
+}
+
+/// Read the EXIF orientation of an image, if present.
+fn read_orientation(file: &mut File) -> u32 {
+    let exif_reader = exif::Reader::new();
+    let mut reader = BufReader::new(file);
+    match exif_reader.read_from_container(&mut reader) {
+        Ok(exif) => match exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
+            Some(orientation) => orientation
+                .value
+                .get_uint(0)
+                .filter(|v| (1..=8).contains(v))
+                .unwrap_or(0),
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Re-encode a JPEG or PNG image into a new temporary file,
+/// applying and stripping any EXIF orientation data.
+///
+/// Returns the new file and the dimensions after rotation.
+fn reencode_image(
+    path: &Path,
+    output_format: image::ImageOutputFormat,
+    (width, height): (usize, usize),
+) -> Result<(NamedTempFile, (usize, usize)), Error> {
+    let mut file = File::open(path).context(Error::IOError)?;
+
+    // Attempt to extract orientation data.
+    let rotation = read_orientation(&mut file);
+    file.seek(SeekFrom::Start(0)).context(Error::IOError)?;
+
+    let image = ImageReader::new(BufReader::new(file))
+        .with_guessed_format()
+        .context(Error::IOError)?
+        .decode()
+        .context(Error::IOError);
+
+    let out_tmp = scratch_file()?;
+    let mut writer = BufWriter::new(out_tmp.reopen().context(Error::IOError)?);
+
+    // See https://jdhao.github.io/2019/07/31/image_rotation_exif_info/
+    match &rotation {
+        2 => image?.fliph(),
+        3 => image?.rotate180(),
+        4 => image?.rotate180().fliph(),
+        5 => image?.rotate90().fliph(),
+        6 => image?.rotate90(),
+        7 => image?.rotate270().fliph(),
+        8 => image?.rotate270(),
+        _ => image?,
+    }
+    .write_to(&mut writer, output_format)
+    .context(Error::IOError)?;
+
+    drop(writer);
+
+    // Calculate dimensions after rotation.
+    let dimensions = match &rotation {
+        2 | 4 | 5 | 7 => (height, width),
+        _ => (width, height),
+    };
+
+    Ok((out_tmp, dimensions))
+}
+
+/// Compute the hex-encoded SHA-256 digest of a file.
+fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut reader = BufReader::new(File::open(path).context(Error::IOError)?);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher).context(Error::IOError)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check whether the start of a file looks like text.
+fn is_text(path: &Path) -> Result<bool, Error> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .context(Error::IOError)?
+        .take(1024)
+        .read_to_end(&mut buf)
+        .context(Error::IOError)?;
+
+    Ok(inspect(&buf).is_text())
+}
+
+/// Work out which category a file falls into from its content type.
+fn categorise(content_type: &str, path: &Path) -> Result<Category, Error> {
+    Ok(match content_type {
+        /* jpg */ "image/jpeg" |
+        /* png */ "image/png" |
+        /* gif */ "image/gif" |
+        /* webp */ "image/webp" => Category::Image,
+        /*  mp4 */ "video/mp4" |
+        /* webm */ "video/webm" |
+        /*  mov */ "video/quicktime" => Category::Video,
+        /* mp3 */ "audio/mpeg" |
+        /* wav */ "audio/wav" |
+        /* ogg */ "audio/x-vorbis+ogg" |
+        /* opus */ "audio/x-opus+ogg" => Category::Audio,
+        _ if is_text(path)? => Category::Text,
+        _ => Category::File,
+    })
+}
+
+/// Who uploaded a file and from where.
+pub struct Origin {
+    pub uploader_id: Option<String>,
+    pub ip_hash: Option<String>,
+}
+
+/// Pick an id for a new file in a tag.
+pub fn generate_id(tag: &Tag) -> String {
+    if tag.use_ulid {
+        ulid::Ulid::new().to_string()
+    } else {
+        nanoid!(42)
+    }
+}
+
+/// Run a received file through the upload pipeline:
+/// detect its type, strip metadata, scan it, store it
+/// and record it in the database.
+pub async fn process(
+    tag_id: &str,
+    tag: &Tag,
+    id: String,
+    filename: String,
+    origin: Origin,
+    mut file: NamedTempFile,
+) -> Result<crate::db::File, Error> {
+    let config = Config::global();
+
+    // ? Find the content-type of the data.
+    let timer = metrics::stage("detect");
+    let path = file.path().to_owned();
+    let mut content_type = web::block(move || tree_magic::from_filepath(&path))
+        .await
+        .context(Error::BlockingError)?;
+    timer.observe_duration();
+
+    // Intercept known file extensions with certain content types
+    if content_type == "application/zip" && filename.to_lowercase().ends_with(".apk") {
+        content_type = "application/vnd.android.package-archive".to_string();
+    }
 
-        // Intercept known file extensions with certain content types
-        if content_type == "application/zip" && filename.to_lowercase().ends_with(".apk") {
-            content_type = "application/vnd.android.package-archive".to_string();
+    if content_type == "application/x-riff" {
+        if filename.to_lowercase().ends_with(".webp") {
+            content_type = "image/webp".to_string();
+        } else if filename.to_lowercase().ends_with(".wav")
+            || filename.to_lowercase().ends_with(".wave")
+        {
+            content_type = "audio/wav".to_string();
         }
+    }
 
-        if content_type == "application/x-riff" {
-            if filename.to_lowercase().ends_with(".webp") {
-                content_type = "image/webp".to_string();
-            } else if filename.to_lowercase().ends_with(".wav")
-                || filename.to_lowercase().ends_with(".wave")
-            {
-                content_type = "audio/wav".to_string();
+    // Check if content type is blocked
+    if config.filter.content_types.contains(&content_type) {
+        return Err(Error::ContentTypeNotAllowed);
+    }
+
+    // ? Scan the upload as received, before we modify it.
+    let category = categorise(&content_type, file.path())?;
+    if tag
+        .scan
+        .as_ref()
+        .unwrap_or(&config.clamd.scan)
+        .contains(&category)
+    {
+        let timer = metrics::stage("clamd");
+        let verdict = virus_scan::check(file.path()).await?;
+        timer.observe_duration();
+
+        if let Verdict::Infected(signature) = verdict {
+            let sample = Sample {
+                tag: tag_id,
+                filename: &filename,
+                content_type: &content_type,
+                origin: &origin,
+                signature,
+            };
+
+            if let Err(error) = quarantine::store(sample, file.path()).await {
+                error!("Failed to quarantine an upload to {}: {}", tag_id, error);
             }
-        }
 
-        // Check if content type is blocked
-        if config.filter.content_types.contains(&content_type) {
-            return Err(Error::ContentTypeNotAllowed);
+            return Err(Error::Malware);
         }
+    }
+
+    let s = &content_type[..];
 
-        let s = &content_type[..];
-
-        let metadata = match s {
-            /* jpg */ "image/jpeg" |
-            /* png */ "image/png" |
-            /* gif */ "image/gif" |
-            /* webp */ "image/webp"  => {
-                if let Ok(imagesize::ImageSize { width, height }) = imagesize::blob_size(&buf) {
-                    if s == "image/jpeg" || s == "image/png" {
-                        let mut cursor = Cursor::new(buf);
-
-                        // Attempt to extract orientation data.
-                        let exif_reader = exif::Reader::new();
-                        let rotation = match exif_reader.read_from_container(&mut cursor) {
-                            Ok(exif) => {
-                                match exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
-                                    Some(orientation) => {
-                                        match orientation.value.get_uint(0) {
-                                            Some(v @ 1..=8) => v,
-                                            _ => 0
-                                        }
-                                    }
-                                    _ => 0
-                                }
-                            }
-                            _ => 0
-                        };
-
-                        cursor.set_position(0);
-
-                        // Re-encode JPEGs to remove EXIF data.
-                        // Also re-encode PNGs to mitigate CVE-2023-21036
-                        let output_format: image::ImageOutputFormat = if s == "image/jpeg" {
-                            image::ImageOutputFormat::Jpeg(config.jpeg_quality)
-                        } else { // It's a PNG
-                            image::ImageOutputFormat::Png
-                        };
-
-                        let image = ImageReader::new(cursor)
-                            .with_guessed_format()
-                            .map_err(|_| Error::IOError)?
-                            .decode()
-                            .map_err(|_| Error::IOError);
-
-                        let mut bytes: Vec<u8> = Vec::new();
-                        let mut writer = Cursor::new(&mut bytes);
-
-                        // See https://jdhao.github.io/2019/07/31/image_rotation_exif_info/
-                        match &rotation {
-                            2 => { image?.fliph() }
-                            3 => { image?.rotate180() }
-                            4 => { image?.rotate180().fliph() }
-                            5 => { image?.rotate90().fliph() }
-                            6 => { image?.rotate90() }
-                            7 => { image?.rotate270().fliph() }
-                            8 => { image?.rotate270() }
-                            _ => { image? }
-                        }
-                        .write_to(&mut writer, output_format)
-                        .map_err(|_| Error::IOError)?;
-
-                        buf = bytes;
-
-                        // Calculate dimensions after rotation.
-                        let (width, height) = match &rotation {
-                            2 | 4 | 5 | 7 => (height, width),
-                            _ => (width, height)
-                        };
-
-                        Metadata::Image {
-                            width: width.try_into().map_err(|_| Error::IOError)?,
-                            height: height.try_into().map_err(|_| Error::IOError)?
-                        }
+    let metadata = match category {
+        Category::Image => {
+            if let Ok(imagesize::ImageSize { width, height }) = imagesize::size(file.path()) {
+                let (width, height) = if s == "image/jpeg" || s == "image/png" {
+                    // Re-encode JPEGs to remove EXIF data.
+                    // Also re-encode PNGs to mitigate CVE-2023-21036
+                    let output_format: image::ImageOutputFormat = if s == "image/jpeg" {
+                        image::ImageOutputFormat::Jpeg(config.jpeg_quality)
                     } else {
-                        // GIFs and WebPs will not be re-encoded.
-                        Metadata::Image {
-                            width: width.try_into().map_err(|_| Error::IOError)?,
-                            height: height.try_into().map_err(|_| Error::IOError)?
-                        }
-                    }
+                        // It's a PNG
+                        image::ImageOutputFormat::Png
+                    };
+
+                    let timer = metrics::stage("reencode");
+                    let (reencoded, dimensions) = web::block(move || {
+                        reencode_image(file.path(), output_format, (width, height))
+                    })
+                    .await
+                    .context(Error::BlockingError)??;
+                    timer.observe_duration();
+
+                    file = reencoded;
+                    dimensions
                 } else {
-                    Metadata::File
-                }
-            }
-            /*  mp4 */ "video/mp4" |
-            /* webm */ "video/webm" |
-            /*  mov */ "video/quicktime" => {
-                let ext = match s {
-                    "video/mp4" => "mp4",
-                    "video/webm" => "webm",
-                    "video/quicktime" => "mov",
-                    _ => unreachable!()
+                    // GIFs and WebPs will not be re-encoded.
+                    (width, height)
                 };
 
-                let mut tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
-                tmp.write_all(&buf).map_err(|_| Error::IOError)?;
-
-                if let Ok(Ok(((width, height), tmp))) = web::block(move || determine_video_size(tmp.path()).map(|t| (t, tmp))).await {
-                    buf = vec![];
-                    let out_tmp = NamedTempFile::new().map_err(|_| Error::IOError)?;
-                    let out_tmp = web::block(move ||
-                        Command::new("ffmpeg")
-                            .args(&[
-                                "-y",                                               // Overwrite the temporary file.
-                                "-i", tmp.path().to_str().ok_or(Error::IOError)?,   // Read the original uploaded file.
-                                "-map_metadata", "-1",                              // Strip any metadata.
-                                "-c:v", "copy", "-c:a", "copy",                     // Copy video / audio data to new file.
-                                "-f", ext,                                          // Select the correct file format.
-                                out_tmp.path().to_str().ok_or(Error::IOError)?])    // Save to new temporary file.
-                            .output()
-                            .map(|_| out_tmp)
-                            .map_err(|_| Error::IOError)
-                    )
-                    .await
-                    .map_err(|_| Error::BlockingError)?
-                    .map_err(|_| Error::IOError)?;
-
-                    let mut file = web::block(move || std::fs::File::open(out_tmp.path()).map(|f| (f, out_tmp)))
-                        .await
-                        .map_err(|_| Error::BlockingError)?
-                        .map_err(|_| Error::IOError)?;
-
-                    buf = web::block(move || file.0.read_to_end(&mut buf).map(|_| buf))
-                        .await
-                        .map_err(|_| Error::BlockingError)?
-                        .map_err(|_| Error::IOError)?;
-
-                    Metadata::Video {
-                        width,
-                        height
-                    }
-                } else {
-                    Metadata::File
+                Metadata::Image {
+                    width: width.try_into().context(Error::IOError)?,
+                    height: height.try_into().context(Error::IOError)?,
                 }
+            } else {
+                Metadata::File
             }
-            /* mp3 */ "audio/mpeg" |
-            /* wav */ "audio/wav" |
-            /* ogg */ "audio/x-vorbis+ogg" |
-            /* opus */ "audio/x-opus+ogg" => {
-                Metadata::Audio
-            }
-            _ => {
-                if inspect(&buf).is_text() {
-                    Metadata::Text
-                } else {
-                    // Scan the file for malware
-                    if *USE_CLAMD {
-                        let scan_response =
-                            revolt_clamav_client::scan_buffer_tcp(&buf, CLAMD_HOST.to_string(), None).unwrap();
-
-                        let file_clean = revolt_clamav_client::clean(&scan_response).unwrap();
-                        if !file_clean {
-                            return Err(Error::Malware)
-                        }
-                    }
-
-                    Metadata::File
-                }
-            }
-        };
-
-        if let Some(content_type) = &tag.restrict_content_type {
-            if !matches!(
-                (content_type, &metadata),
-                (ContentType::Image, Metadata::Image { .. })
-                    | (ContentType::Video, Metadata::Video { .. })
-                    | (ContentType::Audio, Metadata::Audio)
-            ) {
-                return Err(Error::FileTypeNotAllowed);
+        }
+        Category::Video => {
+            let ext = match s {
+                "video/mp4" => "mp4",
+                "video/webm" => "webm",
+                "video/quicktime" => "mov",
+                _ => unreachable!(),
+            };
+
+            let timer = metrics::stage("ffmpeg");
+            let (probe, tmp) = web::block(move || (determine_video_size(file.path()), file))
+                .await
+                .context(Error::BlockingError)?;
+
+            if let Ok((width, height)) = probe {
+                let out_tmp = scratch_file()?;
+                file = web::block(move || {
+                    #[rustfmt::skip]
+                    let args = [
+                        "-y",                                               // Overwrite the temporary file.
+                        "-i", tmp.path().to_str().ok_or(Error::IOError)?,   // Read the original uploaded file.
+                        "-map_metadata", "-1",                              // Strip any metadata.
+                        "-c:v", "copy", "-c:a", "copy",                     // Copy video / audio data to new file.
+                        "-f", ext,                                          // Select the correct file format.
+                        out_tmp.path().to_str().ok_or(Error::IOError)?,     // Save to new temporary file.
+                    ];
+
+                    Command::new("ffmpeg")
+                        .args(args)
+                        .output()
+                        .map(|_| out_tmp)
+                        .context(Error::IOError)
+                })
+                .await
+                .context(Error::BlockingError)?
+                .context(Error::IOError)?;
+                timer.observe_duration();
+
+                Metadata::Video { width, height }
+            } else {
+                timer.observe_duration();
+                file = tmp;
+                Metadata::File
             }
         }
+        Category::Audio => Metadata::Audio,
+        Category::Text => Metadata::Text,
+        Category::File => Metadata::File,
+    };
+
+    if let Some(content_type) = &tag.restrict_content_type {
+        if !matches!(
+            (content_type, &metadata),
+            (ContentType::Image, Metadata::Image { .. })
+                | (ContentType::Video, Metadata::Video { .. })
+                | (ContentType::Audio, Metadata::Audio)
+        ) {
+            return Err(Error::FileTypeNotAllowed);
+        }
+    }
 
-        let id = if tag.use_ulid {
-            ulid::Ulid::new().to_string()
-        } else {
-            nanoid!(42)
-        };
+    let size = file.as_file().metadata().context(Error::IOError)?.len();
+
+    // ? Store identical contents only once per tag.
+    let path = file.path().to_owned();
+    let hash = web::block(move || hash_file(&path))
+        .await
+        .context(Error::BlockingError)??;
+
+    let timer = metrics::stage("storage");
+    store_blob(tag_id, &hash, size as i64, file.path()).await?;
+    timer.observe_duration();
+
+    let file_info = crate::db::File {
+        id,
+        tag: tag_id.to_string(),
+        filename,
+        metadata,
+        content_type,
+        size: size as isize,
+        hash: Some(hash),
+        uploader_id: origin.uploader_id,
+        uploaded_at: Some(DateTime::now()),
+        ip_hash: origin.ip_hash,
+        deleted: None,
+        reported: None,
+    };
+
+    if let Err(error) = get_collection::<crate::db::File>("attachments")
+        .insert_one(&file_info, None)
+        .await
+    {
+        release_blob(tag_id, file_info.storage_key()).await.ok();
+        return Err(error).context(Error::DatabaseError);
+    }
 
-        let file = crate::db::File {
-            id,
-            tag: tag_id.clone(),
-            filename,
-            metadata,
-            content_type,
-            size: buf.len() as isize,
-            deleted: None,
-            reported: None,
-        };
+    metrics::UPLOADS
+        .with_label_values(&[tag_id, &file_info.content_type])
+        .inc();
+    metrics::BYTES_STORED
+        .with_label_values(&[tag_id])
+        .inc_by(size);
 
-        get_collection("attachments")
-            .insert_one(&file, None)
-            .await
-            .map_err(|_| Error::DatabaseError)?;
+    Ok(file_info)
+}
 
-        if *USE_S3 {
-            let bucket = get_s3_bucket(&tag_id)?;
+pub async fn post(req: HttpRequest, mut payload: Multipart) -> Result<HttpResponse, Error> {
+    let (tag_id, tag) = get_tag(&req)?;
+    let uploader = authenticate_upload(&req, tag).await?;
 
-            let (_, code) = bucket
-                .put_object(format!("/{}", file.id), &buf)
-                .await
-                .map_err(|_| Error::S3Error)?;
+    if let Ok(Some(mut field)) = payload.try_next().await {
+        let content_type = field.content_disposition().ok_or(Error::FailedToReceive)?;
+        let filename = content_type
+            .get_filename()
+            .ok_or(Error::FailedToReceive)?
+            .to_string();
 
-            if code != 200 {
-                return Err(Error::S3Error);
-            }
-        } else {
-            let path = format!("{}/{}", *LOCAL_STORAGE_PATH, &file.id);
-            let mut f = web::block(|| std::fs::File::create(path))
-                .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
+        // ? Spool multipart data to disk.
+        let timer = metrics::stage("receive");
+        let file = receive_field(&mut field, tag.max_size).await?;
+        timer.observe_duration();
 
-            web::block(move || f.write_all(&buf))
-                .await
-                .map_err(|_| Error::BlockingError)?
-                .map_err(|_| Error::IOError)?;
-        }
+        let origin = Origin {
+            uploader_id: uploader.map(|uploader| uploader.id),
+            ip_hash: client_ip_hash(&req),
+        };
 
-        Ok(HttpResponse::Ok().json(json!({ "id": file.id })))
+        // ? Check the uploader has room left in this tag.
+        let size = file.as_file().metadata().context(Error::IOError)?.len();
+        quota::check(
+            &tag_id,
+            tag,
+            origin.uploader_id.as_deref(),
+            origin.ip_hash.as_deref(),
+            size,
+        )
+        .await?;
+
+        let file_info = process(&tag_id, tag, generate_id(tag), filename, origin, file).await?;
+        Ok(HttpResponse::Ok().json(json!({ "id": file_info.id })))
     } else {
         Err(Error::MissingData)
     }
@@ -0,0 +1,225 @@
+use crate::config::Config;
+use crate::routes::serve::Resize;
+use crate::util::result::Error;
+use crate::util::variables::SIGNING_SECRET;
+
+use actix_web::web::Query;
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+struct Signature {
+    expires: u64,
+    sig: String,
+}
+
+/// Make sure we can sign URLs if any tag requires it.
+pub fn init() {
+    let config = Config::global();
+    if config.tags.values().any(|tag| tag.require_signature) && SIGNING_SECRET.is_none() {
+        panic!("AUTUMN_SIGNING_SECRET must be set for tags that require signatures.");
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn mac(secret: &str, tag: &str, id: &str, resize: &Resize, expires: u64) -> Hmac<Sha256> {
+    let field = |value: Option<isize>| value.map(|v| v.to_string()).unwrap_or_default();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            tag,
+            id,
+            expires,
+            field(resize.size),
+            field(resize.width),
+            field(resize.height),
+            field(resize.max_side),
+        )
+        .as_bytes(),
+    );
+
+    mac
+}
+
+/// Create the query string for a URL to a file which is valid
+/// until `ttl` has passed, including any resize parameters.
+pub fn sign(tag: &str, id: &str, resize: &Resize, ttl: Duration) -> Result<String, Error> {
+    let secret = SIGNING_SECRET.as_ref().ok_or(Error::InvalidSignature)?;
+    Ok(signed_query(
+        secret,
+        tag,
+        id,
+        resize,
+        unix_time(SystemTime::now() + ttl),
+    ))
+}
+
+fn signed_query(secret: &str, tag: &str, id: &str, resize: &Resize, expires: u64) -> String {
+    let sig = hex::encode(
+        mac(secret, tag, id, resize, expires)
+            .finalize()
+            .into_bytes(),
+    );
+
+    let mut query = vec![];
+    for (key, value) in [
+        ("size", resize.size),
+        ("width", resize.width),
+        ("height", resize.height),
+        ("max_side", resize.max_side),
+    ] {
+        if let Some(value) = value {
+            query.push(format!("{}={}", key, value));
+        }
+    }
+
+    query.push(format!("expires={}", expires));
+    query.push(format!("sig={}", sig));
+    query.join("&")
+}
+
+/// Check the request carries a valid, unexpired signature for
+/// this file and its resize parameters, returning when it expires.
+pub fn verify(req: &HttpRequest, tag: &str, id: &str) -> Result<u64, Error> {
+    let secret = SIGNING_SECRET.as_ref().ok_or(Error::InvalidSignature)?;
+    verify_query(secret, req.query_string(), tag, id, SystemTime::now())
+}
+
+fn verify_query(
+    secret: &str,
+    query: &str,
+    tag: &str,
+    id: &str,
+    now: SystemTime,
+) -> Result<u64, Error> {
+    let Query(signature) =
+        Query::<Signature>::from_query(query).map_err(|_| Error::InvalidSignature)?;
+    let Query(resize) = Query::<Resize>::from_query(query).map_err(|_| Error::InvalidSignature)?;
+
+    if signature.expires < unix_time(now) {
+        return Err(Error::InvalidSignature);
+    }
+
+    let sig = hex::decode(&signature.sig).map_err(|_| Error::InvalidSignature)?;
+    mac(secret, tag, id, &resize, signature.expires)
+        .verify(&sig)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    Ok(signature.expires)
+}
+
+/// Cache-Control for a signed URL, which shared caches mustn't
+/// keep and nobody should keep for longer than it's valid.
+pub fn cache_control(expires: u64) -> String {
+    cache_control_at(expires, SystemTime::now())
+}
+
+fn cache_control_at(expires: u64, now: SystemTime) -> String {
+    format!(
+        "private, max-age={}, must-revalidate",
+        expires.saturating_sub(unix_time(now))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn resize(width: Option<isize>) -> Resize {
+        Resize {
+            size: None,
+            width,
+            height: None,
+            max_side: None,
+        }
+    }
+
+    fn query(resize: &Resize) -> String {
+        let expires = unix_time(SystemTime::now() + Duration::from_secs(60));
+        signed_query(SECRET, "attachments", "id", resize, expires)
+    }
+
+    #[test]
+    fn accepts_own_signature() {
+        let query = query(&resize(Some(100)));
+        assert!(verify_query(SECRET, &query, "attachments", "id", SystemTime::now()).is_ok());
+    }
+
+    #[test]
+    fn keeps_signed_responses_out_of_shared_caches() {
+        let now = SystemTime::now();
+        let query = signed_query(
+            SECRET,
+            "attachments",
+            "id",
+            &resize(None),
+            unix_time(now) + 60,
+        );
+
+        let expires = verify_query(SECRET, &query, "attachments", "id", now).unwrap();
+        assert_eq!(
+            cache_control_at(expires, now),
+            "private, max-age=60, must-revalidate"
+        );
+        assert_eq!(
+            cache_control_at(expires, now + Duration::from_secs(120)),
+            "private, max-age=0, must-revalidate"
+        );
+    }
+
+    #[test]
+    fn rejects_expired_signature() {
+        let query = query(&resize(None));
+        let later = SystemTime::now() + Duration::from_secs(120);
+        assert!(verify_query(SECRET, &query, "attachments", "id", later).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_parameters() {
+        let query = query(&resize(Some(100)));
+        let now = SystemTime::now();
+
+        let tampered = query.replace("width=100", "width=4000");
+        assert!(verify_query(SECRET, &tampered, "attachments", "id", now).is_err());
+
+        let added = format!("height=4000&{}", query);
+        assert!(verify_query(SECRET, &added, "attachments", "id", now).is_err());
+
+        assert!(verify_query(SECRET, &query, "attachments", "other", now).is_err());
+        assert!(verify_query(SECRET, &query, "avatars", "id", now).is_err());
+        assert!(verify_query("other", &query, "attachments", "id", now).is_err());
+    }
+
+    #[test]
+    fn rejects_extended_expiry() {
+        let query = query(&resize(None));
+        let (rest, sig) = query.split_once("&sig=").unwrap();
+        let (_, expires) = rest.split_once("expires=").unwrap();
+        let extended = query.replace(
+            &format!("expires={}", expires),
+            &format!("expires={}", expires.parse::<u64>().unwrap() + 3600),
+        );
+
+        assert!(extended.ends_with(sig));
+        assert!(verify_query(SECRET, &extended, "attachments", "id", SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        assert!(verify_query(SECRET, "width=100", "attachments", "id", SystemTime::now()).is_err());
+    }
+}
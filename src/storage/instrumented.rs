@@ -0,0 +1,77 @@
+use super::{ByteStream, Object, ObjectInfo, PresignedUpload, StorageBackend};
+use crate::metrics::STORAGE_ERRORS;
+use crate::util::result::Error;
+
+use async_trait::async_trait;
+use std::path::Path;
+
+/// Wraps another backend, counting failed operations.
+pub struct Instrumented(pub Box<dyn StorageBackend>);
+
+fn count<T>(operation: &str, result: Result<T, Error>) -> Result<T, Error> {
+    match &result {
+        Err(Error::NotFound) | Ok(_) => {}
+        Err(_) => STORAGE_ERRORS.with_label_values(&[operation]).inc(),
+    }
+
+    result
+}
+
+#[async_trait]
+impl StorageBackend for Instrumented {
+    async fn put(&self, tag: &str, id: &str, data: Vec<u8>) -> Result<(), Error> {
+        count("put", self.0.put(tag, id, data).await)
+    }
+
+    async fn put_file(&self, tag: &str, id: &str, path: &Path) -> Result<(), Error> {
+        count("put", self.0.put_file(tag, id, path).await)
+    }
+
+    async fn get(&self, tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+        count("get", self.0.get(tag, id).await)
+    }
+
+    async fn delete(&self, tag: &str, id: &str) -> Result<(), Error> {
+        count("delete", self.0.delete(tag, id).await)
+    }
+
+    async fn head(&self, tag: &str, id: &str) -> Result<ObjectInfo, Error> {
+        count("head", self.0.head(tag, id).await)
+    }
+
+    async fn stream(&self, tag: &str, id: &str) -> Result<Object, Error> {
+        count("get", self.0.stream(tag, id).await)
+    }
+
+    async fn stream_range(
+        &self,
+        tag: &str,
+        id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<ByteStream, Error> {
+        count("get", self.0.stream_range(tag, id, start, length).await)
+    }
+
+    async fn list(&self, tag: &str) -> Result<Vec<String>, Error> {
+        count("list", self.0.list(tag).await)
+    }
+
+    async fn list_legacy(&self) -> Result<Vec<String>, Error> {
+        count("list", self.0.list_legacy().await)
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        count("check", self.0.check().await)
+    }
+
+    fn presign_upload(
+        &self,
+        tag: &str,
+        id: &str,
+        size: u64,
+        expiry: u32,
+    ) -> Result<PresignedUpload, Error> {
+        count("presign", self.0.presign_upload(tag, id, size, expiry))
+    }
+}
@@ -0,0 +1,252 @@
+use super::{ByteStream, Object, ObjectInfo, StorageBackend};
+use crate::util::result::Error;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::error;
+use nanoid::nanoid;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// Stores objects as files in a directory per tag.
+///
+/// Objects written before tags had their own directories
+/// are still read from the root directory.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub async fn new(root: String) -> std::io::Result<LocalBackend> {
+        fs::create_dir_all(&root).await?;
+        Ok(LocalBackend { root: root.into() })
+    }
+
+    /// Where a new object should be written.
+    async fn create_path(&self, tag: &str, id: &str) -> Result<PathBuf, Error> {
+        let dir = self.root.join(tag);
+        fs::create_dir_all(&dir).await.map_err(map_io_error)?;
+        Ok(dir.join(id))
+    }
+
+    /// Where an existing object can be found.
+    async fn path(&self, tag: &str, id: &str) -> PathBuf {
+        let path = self.root.join(tag).join(id);
+        if fs::metadata(&path).await.is_ok() {
+            path
+        } else {
+            self.root.join(id)
+        }
+    }
+}
+
+/// Somewhere to write an object before moving it into place.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.{}.tmp", name, nanoid!(8)))
+}
+
+/// Move a fully written object into place, so readers
+/// never see one which is only partially written.
+async fn finish(tmp: &Path, path: &Path, written: std::io::Result<()>) -> Result<(), Error> {
+    let result = match written {
+        Ok(()) => fs::rename(tmp, path).await,
+        Err(error) => Err(error),
+    };
+
+    if result.is_err() {
+        fs::remove_file(tmp).await.ok();
+    }
+
+    result.map_err(map_io_error)
+}
+
+fn map_io_error(error: std::io::Error) -> Error {
+    match error.kind() {
+        ErrorKind::NotFound => Error::NotFound,
+        _ => {
+            error!("Local storage operation failed: {}", error);
+            Error::IOError
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(&self, tag: &str, id: &str, data: Vec<u8>) -> Result<(), Error> {
+        let path = self.create_path(tag, id).await?;
+        let tmp = temp_path(&path);
+        finish(&tmp, &path, fs::write(&tmp, data).await).await
+    }
+
+    async fn put_file(&self, tag: &str, id: &str, path: &Path) -> Result<(), Error> {
+        let dest = self.create_path(tag, id).await?;
+        let tmp = temp_path(&dest);
+        finish(&tmp, &dest, fs::copy(path, &tmp).await.map(|_| ())).await
+    }
+
+    async fn get(&self, tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+        fs::read(self.path(tag, id).await)
+            .await
+            .map_err(map_io_error)
+    }
+
+    async fn delete(&self, tag: &str, id: &str) -> Result<(), Error> {
+        fs::remove_file(self.path(tag, id).await)
+            .await
+            .map_err(map_io_error)
+    }
+
+    async fn head(&self, tag: &str, id: &str) -> Result<ObjectInfo, Error> {
+        let metadata = fs::metadata(self.path(tag, id).await)
+            .await
+            .map_err(map_io_error)?;
+        Ok(ObjectInfo {
+            size: metadata.len(),
+            last_modified: metadata.modified().ok(),
+        })
+    }
+
+    async fn stream(&self, tag: &str, id: &str) -> Result<Object, Error> {
+        let file = fs::File::open(self.path(tag, id).await)
+            .await
+            .map_err(map_io_error)?;
+        let metadata = file.metadata().await.map_err(map_io_error)?;
+
+        Ok(Object {
+            info: ObjectInfo {
+                size: metadata.len(),
+                last_modified: metadata.modified().ok(),
+            },
+            body: ReaderStream::new(file)
+                .map(|chunk| chunk.map_err(map_io_error))
+                .boxed(),
+        })
+    }
+
+    async fn stream_range(
+        &self,
+        tag: &str,
+        id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<ByteStream, Error> {
+        let mut file = fs::File::open(self.path(tag, id).await)
+            .await
+            .map_err(map_io_error)?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(map_io_error)?;
+
+        Ok(ReaderStream::new(file.take(length))
+            .map(|chunk| chunk.map_err(map_io_error))
+            .boxed())
+    }
+
+    async fn list(&self, tag: &str) -> Result<Vec<String>, Error> {
+        let mut entries = match fs::read_dir(self.root.join(tag)).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(map_io_error(error)),
+        };
+
+        let mut keys = vec![];
+        while let Some(entry) = entries.next_entry().await.map_err(map_io_error)? {
+            // Skip objects still being written.
+            if let Some(name) = entry.file_name().to_str() {
+                if !name.starts_with('.') {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn list_legacy(&self) -> Result<Vec<String>, Error> {
+        let mut entries = fs::read_dir(&self.root).await.map_err(map_io_error)?;
+
+        let mut keys = vec![];
+        while let Some(entry) = entries.next_entry().await.map_err(map_io_error)? {
+            // Skip tag directories and anything still being written.
+            let is_file = entry.file_type().await.map_err(map_io_error)?.is_file();
+            if let Some(name) = entry.file_name().to_str() {
+                if is_file && !name.starts_with('.') {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        // Unique per check, replicas may share the directory.
+        let path = self.root.join(format!(".check-{}", nanoid!(16)));
+        fs::write(&path, b"").await.map_err(map_io_error)?;
+        fs::remove_file(&path).await.map_err(map_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_objects_into_place() {
+        let root = tempfile::tempdir().unwrap();
+        let backend = LocalBackend::new(root.path().to_string_lossy().to_string())
+            .await
+            .unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut source, b"contents").unwrap();
+
+        backend
+            .put_file("tag", "file", source.path())
+            .await
+            .unwrap();
+        backend
+            .put("tag", "other", b"other".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(backend.get("tag", "file").await.unwrap(), b"contents");
+
+        // Nothing is left behind under a temporary name.
+        let mut keys = backend.list("tag").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["file", "other"]);
+        assert_eq!(
+            std::fs::read_dir(root.path().join("tag")).unwrap().count(),
+            2
+        );
+
+        // A failed copy leaves no object behind.
+        assert!(backend
+            .put_file("tag", "missing", &root.path().join("nothing"))
+            .await
+            .is_err());
+        assert!(matches!(
+            backend.head("tag", "missing").await,
+            Err(Error::NotFound)
+        ));
+        assert_eq!(
+            std::fs::read_dir(root.path().join("tag")).unwrap().count(),
+            2
+        );
+
+        // Legacy objects sit at the root, beside the tag directories.
+        std::fs::write(root.path().join("legacy"), b"legacy").unwrap();
+        std::fs::write(root.path().join(".check-abc"), b"").unwrap();
+        assert_eq!(backend.list_legacy().await.unwrap(), vec!["legacy"]);
+        assert_eq!(backend.get("tag", "legacy").await.unwrap(), b"legacy");
+    }
+}
@@ -0,0 +1,146 @@
+use super::{ByteStream, Object, ObjectInfo, StorageBackend};
+use crate::util::result::{Error, ResultExt};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Keeps every object in memory, useful for tests and local development.
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: RwLock<HashMap<(String, String), (Bytes, SystemTime)>>,
+}
+
+impl MemoryBackend {
+    fn read(&self, tag: &str, id: &str) -> Result<(Bytes, ObjectInfo), Error> {
+        self.objects
+            .read()
+            .context(Error::IOError)?
+            .get(&(tag.to_string(), id.to_string()))
+            .map(|(data, modified)| {
+                (
+                    data.clone(),
+                    ObjectInfo {
+                        size: data.len() as u64,
+                        last_modified: Some(*modified),
+                    },
+                )
+            })
+            .ok_or(Error::NotFound)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put(&self, tag: &str, id: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.objects.write().context(Error::IOError)?.insert(
+            (tag.to_string(), id.to_string()),
+            (data.into(), SystemTime::now()),
+        );
+
+        Ok(())
+    }
+
+    async fn put_file(&self, tag: &str, id: &str, path: &Path) -> Result<(), Error> {
+        let data = tokio::fs::read(path).await.context(Error::IOError)?;
+        self.put(tag, id, data).await
+    }
+
+    async fn get(&self, tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+        self.read(tag, id).map(|(data, _)| data.to_vec())
+    }
+
+    async fn delete(&self, tag: &str, id: &str) -> Result<(), Error> {
+        self.objects
+            .write()
+            .context(Error::IOError)?
+            .remove(&(tag.to_string(), id.to_string()))
+            .map(|_| ())
+            .ok_or(Error::NotFound)
+    }
+
+    async fn head(&self, tag: &str, id: &str) -> Result<ObjectInfo, Error> {
+        self.read(tag, id).map(|(_, info)| info)
+    }
+
+    async fn stream(&self, tag: &str, id: &str) -> Result<Object, Error> {
+        let (data, info) = self.read(tag, id)?;
+
+        Ok(Object {
+            info,
+            body: futures::stream::once(async move { Ok(data) }).boxed(),
+        })
+    }
+
+    async fn stream_range(
+        &self,
+        tag: &str,
+        id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<ByteStream, Error> {
+        let (data, _) = self.read(tag, id)?;
+        let end = start
+            .checked_add(length)
+            .ok_or(Error::RangeNotSatisfiable)?;
+        if end > data.len() as u64 {
+            return Err(Error::RangeNotSatisfiable);
+        }
+
+        let data = data.slice(start as usize..end as usize);
+        Ok(futures::stream::once(async move { Ok(data) }).boxed())
+    }
+
+    async fn list(&self, tag: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .objects
+            .read()
+            .context(Error::IOError)?
+            .keys()
+            .filter(|(object_tag, _)| object_tag == tag)
+            .map(|(_, id)| id.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn reads_ranges_within_the_object() {
+        let backend = MemoryBackend::default();
+        backend
+            .put("tag", "id", b"0123456789".to_vec())
+            .await
+            .unwrap();
+
+        let range: Vec<Bytes> = backend
+            .stream_range("tag", "id", 2, 3)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(range, vec![Bytes::from_static(b"234")]);
+
+        assert!(matches!(
+            backend.stream_range("tag", "id", 8, 3).await,
+            Err(Error::RangeNotSatisfiable)
+        ));
+        assert!(matches!(
+            backend.stream_range("tag", "id", u64::MAX, 1).await,
+            Err(Error::RangeNotSatisfiable)
+        ));
+        assert!(matches!(
+            backend.stream_range("tag", "missing", 0, 1).await,
+            Err(Error::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::util::result::Error;
+use crate::util::variables::{LOCAL_STORAGE_PATH, STORAGE_BACKEND};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+pub mod instrumented;
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send>>;
+
+/// Information about a stored object that can be
+/// fetched without reading its contents.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// An object being read from storage.
+pub struct Object {
+    pub info: ObjectInfo,
+    pub body: ByteStream,
+}
+
+/// Form a client can POST an object to, sending
+/// `fields` followed by the file itself.
+#[derive(Serialize, Debug)]
+pub struct PresignedUpload {
+    pub url: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Somewhere we can put files.
+///
+/// Objects are addressed by the tag they were uploaded
+/// to and their id, backends decide how that maps onto
+/// their own layout (e.g. one S3 bucket per tag).
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Write an object, replacing any existing object with the same id.
+    async fn put(&self, tag: &str, id: &str, data: Vec<u8>) -> Result<(), Error>;
+
+    /// Write an object from a file on disk without
+    /// reading the whole file into memory.
+    async fn put_file(&self, tag: &str, id: &str, path: &Path) -> Result<(), Error>;
+
+    /// Read an entire object into memory.
+    async fn get(&self, tag: &str, id: &str) -> Result<Vec<u8>, Error>;
+
+    /// Remove an object.
+    async fn delete(&self, tag: &str, id: &str) -> Result<(), Error>;
+
+    /// Fetch information about an object.
+    async fn head(&self, tag: &str, id: &str) -> Result<ObjectInfo, Error>;
+
+    /// Read an object as a stream of chunks.
+    async fn stream(&self, tag: &str, id: &str) -> Result<Object, Error>;
+
+    /// Read `length` bytes of an object starting at `start` as a stream of chunks.
+    async fn stream_range(
+        &self,
+        tag: &str,
+        id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<ByteStream, Error>;
+
+    /// List the keys of every object stored under a tag.
+    async fn list(&self, tag: &str) -> Result<Vec<String>, Error>;
+
+    /// List the keys of objects stored before tags were kept apart,
+    /// which are found under the empty tag.
+    async fn list_legacy(&self) -> Result<Vec<String>, Error> {
+        Ok(vec![])
+    }
+
+    /// Create a form the client can upload an object of exactly `size`
+    /// bytes to directly, valid for `expiry` seconds, if the backend
+    /// supports it.
+    fn presign_upload(
+        &self,
+        _tag: &str,
+        _id: &str,
+        _size: u64,
+        _expiry: u32,
+    ) -> Result<PresignedUpload, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Check the backend is reachable and can be written to.
+    async fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+static BACKEND: OnceCell<Box<dyn StorageBackend>> = OnceCell::new();
+
+/// Select and initialise the storage backend.
+pub async fn init() {
+    let backend: Box<dyn StorageBackend> = match STORAGE_BACKEND.as_ref() {
+        "s3" => {
+            info!("Using S3 storage, make sure your S3 buckets exist!");
+            Box::new(s3::S3Backend)
+        }
+        "memory" => {
+            info!("Using in-memory storage, files will not persist!");
+            Box::new(memory::MemoryBackend::default())
+        }
+        "local" => {
+            info!("Ensuring local storage directory exists.");
+            Box::new(
+                local::LocalBackend::new(LOCAL_STORAGE_PATH.to_string())
+                    .await
+                    .expect("Failed to create local storage directory."),
+            )
+        }
+        other => panic!("Unknown storage backend '{}'.", other),
+    };
+
+    if BACKEND
+        .set(Box::new(instrumented::Instrumented(backend)))
+        .is_err()
+    {
+        panic!("Storage backend was already initialised.");
+    }
+}
+
+/// Get the configured storage backend.
+pub fn get() -> &'static dyn StorageBackend {
+    BACKEND
+        .get()
+        .expect("Storage backend is not initialized.")
+        .as_ref()
+}
+
+/// Use in-memory storage in tests, unless a backend is already set up.
+#[cfg(test)]
+pub fn init_memory() {
+    BACKEND.get_or_init(|| Box::new(memory::MemoryBackend::default()));
+}
@@ -0,0 +1,277 @@
+use super::{ByteStream, Object, ObjectInfo, PresignedUpload, StorageBackend};
+use crate::config::Config;
+use crate::util::result::{Error, ResultExt};
+use crate::util::variables::get_s3_bucket;
+
+use actix_web::http::header::{HttpDate, LAST_MODIFIED};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use log::error;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// How long pre-signed URLs used internally remain valid for.
+static PRESIGN_EXPIRY: u32 = 60;
+
+/// Shared HTTP client used for streaming objects out of S3.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Parse an HTTP date as returned by S3.
+fn parse_date(value: &str) -> Option<SystemTime> {
+    value.parse::<HttpDate>().ok().map(SystemTime::from)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length.");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Stores objects in S3, using one bucket per tag.
+pub struct S3Backend;
+
+fn check_status(code: u16) -> Result<(), Error> {
+    match code {
+        200..=299 => Ok(()),
+        404 => Err(Error::NotFound),
+        _ => {
+            error!("S3 request failed with status {}", code);
+            Err(Error::S3Error)
+        }
+    }
+}
+
+impl S3Backend {
+    /// Send a GET request for an object, optionally
+    /// restricted to a range of bytes.
+    async fn fetch(
+        &self,
+        tag: &str,
+        id: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<reqwest::Response, Error> {
+        // rust-s3 can only stream into a blocking writer, so we
+        // pre-sign a request and stream the response body ourselves.
+        let bucket = get_s3_bucket(tag)?;
+        let url = bucket
+            .presign_get(format!("/{}", id), PRESIGN_EXPIRY)
+            .context(Error::S3Error)?;
+
+        let mut request = CLIENT.get(url);
+        if let Some((start, end)) = range {
+            request = request.header("Range", format!("bytes={}-{}", start, end));
+        }
+
+        let response = request.send().await.context(Error::S3Error)?;
+        check_status(response.status().as_u16())?;
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(&self, tag: &str, id: &str, data: Vec<u8>) -> Result<(), Error> {
+        let bucket = get_s3_bucket(tag)?;
+        let (_, code) = bucket
+            .put_object(format!("/{}", id), &data)
+            .await
+            .context(Error::S3Error)?;
+
+        check_status(code)
+    }
+
+    async fn put_file(&self, tag: &str, id: &str, path: &Path) -> Result<(), Error> {
+        let bucket = get_s3_bucket(tag)?;
+        let mut file = tokio::fs::File::open(path).await.context(Error::IOError)?;
+
+        // Large files are sent as a multipart upload, one chunk at a time.
+        let code = bucket
+            .put_object_stream(&mut file, format!("/{}", id))
+            .await
+            .context(Error::S3Error)?;
+
+        check_status(code)
+    }
+
+    async fn get(&self, tag: &str, id: &str) -> Result<Vec<u8>, Error> {
+        let bucket = get_s3_bucket(tag)?;
+        let (data, code) = bucket
+            .get_object(format!("/{}", id))
+            .await
+            .context(Error::S3Error)?;
+
+        check_status(code)?;
+        Ok(data)
+    }
+
+    async fn delete(&self, tag: &str, id: &str) -> Result<(), Error> {
+        let bucket = get_s3_bucket(tag)?;
+        let (_, code) = bucket
+            .delete_object(format!("/{}", id))
+            .await
+            .context(Error::S3Error)?;
+
+        check_status(code)
+    }
+
+    async fn head(&self, tag: &str, id: &str) -> Result<ObjectInfo, Error> {
+        let bucket = get_s3_bucket(tag)?;
+        let (head, code) = bucket
+            .head_object(format!("/{}", id))
+            .await
+            .context(Error::S3Error)?;
+
+        check_status(code)?;
+        Ok(ObjectInfo {
+            size: head.content_length.ok_or(Error::S3Error)? as u64,
+            last_modified: head.last_modified.as_deref().and_then(parse_date),
+        })
+    }
+
+    async fn stream(&self, tag: &str, id: &str) -> Result<Object, Error> {
+        let response = self.fetch(tag, id, None).await?;
+
+        Ok(Object {
+            info: ObjectInfo {
+                size: response.content_length().ok_or(Error::S3Error)?,
+                last_modified: response
+                    .headers()
+                    .get(LAST_MODIFIED.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_date),
+            },
+            body: response
+                .bytes_stream()
+                .map(|chunk| chunk.context(Error::S3Error))
+                .boxed(),
+        })
+    }
+
+    async fn stream_range(
+        &self,
+        tag: &str,
+        id: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<ByteStream, Error> {
+        if length == 0 {
+            return Ok(futures::stream::empty().boxed());
+        }
+
+        let response = self
+            .fetch(tag, id, Some((start, start + length - 1)))
+            .await?;
+
+        Ok(response
+            .bytes_stream()
+            .map(|chunk| chunk.context(Error::S3Error))
+            .boxed())
+    }
+
+    async fn list(&self, tag: &str) -> Result<Vec<String>, Error> {
+        let pages = get_s3_bucket(tag)?
+            .list(String::new(), None)
+            .await
+            .context(Error::S3Error)?;
+
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key.trim_start_matches('/').to_string())
+            .collect())
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        // rust-s3 can't HEAD a bucket, so list at most one key instead.
+        let config = Config::global();
+        let quarantine = Some(&config.quarantine)
+            .filter(|quarantine| quarantine.enabled)
+            .map(|quarantine| &quarantine.location);
+
+        for bucket in config.tags.keys().chain(quarantine) {
+            let (_, code) = get_s3_bucket(bucket)?
+                .list_page(String::new(), None, None, None, Some(1))
+                .await
+                .context(Error::S3Error)?;
+
+            check_status(code)?;
+        }
+
+        Ok(())
+    }
+
+    fn presign_upload(
+        &self,
+        tag: &str,
+        id: &str,
+        size: u64,
+        expiry: u32,
+    ) -> Result<PresignedUpload, Error> {
+        // rust-s3 can't restrict the size of a pre-signed PUT,
+        // so we sign a POST policy ourselves instead.
+        let bucket = get_s3_bucket(tag)?;
+        let access_key = bucket.access_key().ok_or(Error::S3Error)?;
+        let secret_key = bucket.secret_key().ok_or(Error::S3Error)?;
+        let token = bucket.security_token().or_else(|| bucket.session_token());
+
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let region = bucket.region().to_string();
+        let credential = format!("{}/{}/{}/s3/aws4_request", access_key, date, region);
+
+        let mut fields = BTreeMap::new();
+        fields.insert("key".to_string(), id.to_string());
+        fields.insert(
+            "x-amz-algorithm".to_string(),
+            "AWS4-HMAC-SHA256".to_string(),
+        );
+        fields.insert("x-amz-credential".to_string(), credential);
+        fields.insert(
+            "x-amz-date".to_string(),
+            now.format("%Y%m%dT%H%M%SZ").to_string(),
+        );
+
+        if let Some(token) = token {
+            fields.insert("x-amz-security-token".to_string(), token.to_string());
+        }
+
+        let mut conditions = vec![
+            json!({ "bucket": bucket.name() }),
+            json!(["content-length-range", size, size]),
+        ];
+        conditions.extend(fields.iter().map(|(name, value)| json!({ name: value })));
+
+        let policy = base64::encode(
+            json!({
+                "expiration": (now + chrono::Duration::seconds(expiry as i64))
+                    .format("%Y-%m-%dT%H:%M:%S.000Z")
+                    .to_string(),
+                "conditions": conditions
+            })
+            .to_string(),
+        );
+
+        let key = [date.as_str(), &region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| {
+                hmac(&key, part.as_bytes())
+            });
+
+        fields.insert(
+            "x-amz-signature".to_string(),
+            hex::encode(hmac(&key, policy.as_bytes())),
+        );
+        fields.insert("policy".to_string(), policy);
+
+        Ok(PresignedUpload {
+            url: bucket.url(),
+            fields,
+        })
+    }
+}
@@ -0,0 +1,247 @@
+use crate::storage;
+use crate::util::result::Error;
+
+use actix_web::http::header::{
+    self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified,
+};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Compare two times at the one second precision used by HTTP dates.
+fn not_after(time: SystemTime, since: SystemTime) -> bool {
+    match (
+        time.duration_since(UNIX_EPOCH),
+        since.duration_since(UNIX_EPOCH),
+    ) {
+        (Ok(time), Ok(since)) => time.as_secs() <= since.as_secs(),
+        _ => false,
+    }
+}
+
+/// Whether the If-None-Match header says the client already has this representation.
+pub fn etag_matches(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// Whether the If-Range header allows us to send only part of this representation.
+pub fn range_applies(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(IfRange::Date(date)) => last_modified
+            .map(|modified| not_after(modified, date.into()))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Build a `304 Not Modified` response carrying the validators for a file.
+pub fn not_modified_response(
+    etag: EntityTag,
+    last_modified: Option<SystemTime>,
+    cache_control: &str,
+) -> HttpResponse {
+    let mut builder = HttpResponse::NotModified();
+    builder
+        .insert_header(header::ETag(etag))
+        .insert_header(("Cache-Control", cache_control));
+
+    if let Some(last_modified) = last_modified {
+        builder.insert_header(LastModified(HttpDate::from(last_modified)));
+    }
+
+    builder.finish()
+}
+
+/// Check the request's conditional headers against a stored object.
+///
+/// Returns a `304 Not Modified` response if the client's cached copy is still good.
+/// If-None-Match is checked without touching storage, If-Modified-Since
+/// is only consulted when there is no If-None-Match header.
+pub async fn check(
+    req: &HttpRequest,
+    tag: &str,
+    key: &str,
+    etag: &EntityTag,
+    cache_control: &str,
+) -> Result<Option<HttpResponse>, Error> {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return Ok(if etag_matches(req, etag) {
+            Some(not_modified_response(etag.clone(), None, cache_control))
+        } else {
+            None
+        });
+    }
+
+    if let Some(IfModifiedSince(since)) = req.get_header::<IfModifiedSince>() {
+        let last_modified = storage::get().head(tag, key).await?.last_modified;
+        if let Some(modified) = last_modified {
+            if not_after(modified, since.into()) {
+                return Ok(Some(not_modified_response(
+                    etag.clone(),
+                    last_modified,
+                    cache_control,
+                )));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    fn etag() -> EntityTag {
+        EntityTag::strong("abc".to_string())
+    }
+
+    fn request(headers: &[(&str, String)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(TestRequest::default(), |req, (name, value)| {
+                req.insert_header((*name, value.as_str()))
+            })
+            .to_http_request()
+    }
+
+    fn date(time: SystemTime) -> String {
+        HttpDate::from(time).to_string()
+    }
+
+    #[test]
+    fn compares_at_second_precision() {
+        let time = UNIX_EPOCH + Duration::from_millis(10_500);
+        assert!(not_after(time, UNIX_EPOCH + Duration::from_secs(10)));
+        assert!(!not_after(time, UNIX_EPOCH + Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        assert!(etag_matches(
+            &request(&[("If-None-Match", "\"abc\"".into())]),
+            &etag()
+        ));
+        assert!(etag_matches(
+            &request(&[("If-None-Match", "W/\"abc\"".into())]),
+            &etag()
+        ));
+        assert!(etag_matches(
+            &request(&[("If-None-Match", "*".into())]),
+            &etag()
+        ));
+        assert!(!etag_matches(
+            &request(&[("If-None-Match", "\"def\"".into())]),
+            &etag()
+        ));
+        assert!(!etag_matches(&request(&[]), &etag()));
+    }
+
+    #[test]
+    fn applies_ranges_if_unchanged() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert!(range_applies(&request(&[]), &etag(), None));
+        assert!(range_applies(
+            &request(&[("If-Range", "\"abc\"".into())]),
+            &etag(),
+            None
+        ));
+        // Weak validators can't be used for ranges.
+        assert!(!range_applies(
+            &request(&[("If-Range", "W/\"abc\"".into())]),
+            &etag(),
+            None
+        ));
+        assert!(range_applies(
+            &request(&[("If-Range", date(modified))]),
+            &etag(),
+            Some(modified)
+        ));
+        assert!(!range_applies(
+            &request(&[("If-Range", date(modified - Duration::from_secs(1)))]),
+            &etag(),
+            Some(modified)
+        ));
+        assert!(!range_applies(
+            &request(&[("If-Range", date(modified))]),
+            &etag(),
+            None
+        ));
+    }
+
+    #[tokio::test]
+    async fn answers_conditional_requests() {
+        storage::init_memory();
+        storage::get()
+            .put("conditional", "file", b"data".to_vec())
+            .await
+            .unwrap();
+
+        let check = |headers: Vec<(&'static str, String)>| async move {
+            check(
+                &request(&headers),
+                "conditional",
+                "file",
+                &etag(),
+                crate::CACHE_CONTROL,
+            )
+            .await
+            .unwrap()
+            .map(|response| response.status())
+        };
+
+        let later = date(SystemTime::now() + Duration::from_secs(60));
+        let earlier = date(SystemTime::now() - Duration::from_secs(60));
+
+        assert_eq!(check(vec![]).await, None);
+        assert_eq!(
+            check(vec![("If-None-Match", "\"abc\"".into())]).await,
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(check(vec![("If-None-Match", "\"def\"".into())]).await, None);
+        assert_eq!(
+            check(vec![("If-Modified-Since", later.clone())]).await,
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(check(vec![("If-Modified-Since", earlier)]).await, None);
+
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert_eq!(
+            check(vec![
+                ("If-None-Match", "\"def\"".into()),
+                ("If-Modified-Since", later)
+            ])
+            .await,
+            None
+        );
+
+        // Signed responses keep their own Cache-Control when not modified.
+        let response = super::check(
+            &request(&[("If-None-Match", "\"abc\"".into())]),
+            "conditional",
+            "file",
+            &etag(),
+            "private, max-age=60, must-revalidate",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            response.headers().get("Cache-Control").unwrap(),
+            "private, max-age=60, must-revalidate"
+        );
+    }
+}
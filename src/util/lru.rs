@@ -0,0 +1,132 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Map which remembers the order its entries were last used in,
+/// so the least recently used can be dropped first.
+pub struct Lru<K, V> {
+    /// Each value along with when it was last used.
+    entries: HashMap<K, (V, u64)>,
+    /// Keys ordered by last use.
+    recency: BTreeMap<u64, K>,
+    /// Monotonic counter used to order uses.
+    clock: u64,
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+
+    /// Get a value, marking it as the most recently used.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let clock = self.tick();
+        let (key, _) = self.entries.get_key_value(key)?;
+        let key = key.clone();
+
+        let (value, last_used) = self.entries.get_mut::<Q>(key.borrow())?;
+        self.recency.remove(last_used);
+        *last_used = clock;
+        self.recency.insert(clock, key);
+        Some(value)
+    }
+
+    /// Insert a value as the most recently used, returning any it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.remove(&key);
+        let clock = self.tick();
+        self.recency.insert(clock, key.clone());
+        self.entries.insert(key, (value, clock));
+        previous
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        Some(value)
+    }
+
+    /// Remove the least recently used entry.
+    pub fn pop_oldest(&mut self) -> Option<(K, V)> {
+        let (_, key) = self.recency.pop_first()?;
+        let (value, _) = self.entries.remove(&key)?;
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_least_recently_used_first() {
+        let mut lru = Lru::default();
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        lru.insert("c", 3);
+
+        // Using "a" makes "b" the oldest.
+        assert_eq!(lru.get_mut(&"a"), Some(&mut 1));
+        assert_eq!(lru.pop_oldest(), Some(("b", 2)));
+
+        // Replacing "c" makes "a" the oldest.
+        assert_eq!(lru.insert("c", 4), Some(3));
+        assert_eq!(lru.pop_oldest(), Some(("a", 1)));
+        assert_eq!(lru.pop_oldest(), Some(("c", 4)));
+        assert_eq!(lru.pop_oldest(), None);
+        assert!(lru.is_empty());
+    }
+
+    #[test]
+    fn forgets_removed_entries() {
+        let mut lru = Lru::default();
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+
+        assert_eq!(lru.remove(&"a"), Some(1));
+        assert_eq!(lru.remove(&"a"), None);
+        assert_eq!(lru.get_mut(&"a"), None);
+        assert!(!lru.contains_key(&"a"));
+        assert_eq!(lru.len(), 1);
+        assert_eq!(lru.pop_oldest(), Some(("b", 2)));
+    }
+}
@@ -1,2 +1,5 @@
+pub mod conditional;
+pub mod lru;
+pub mod range;
 pub mod result;
 pub mod variables;
@@ -0,0 +1,223 @@
+use crate::storage::ByteStream;
+use crate::util::result::Error;
+
+use actix_files::HttpRange;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use bytes::Bytes;
+use futures::future::Future;
+use futures::{stream, StreamExt, TryStreamExt};
+use http_range::HttpRangeParseError;
+use nanoid::nanoid;
+
+/// Which parts of a file the client asked for.
+pub enum RangeRequest {
+    /// No usable Range header, or too many ranges, send the whole file.
+    Full,
+    /// One or more satisfiable byte ranges.
+    Partial(Vec<HttpRange>),
+    /// The Range header cannot be satisfied for this file.
+    Unsatisfiable,
+}
+
+/// Most ranges we serve as multipart, more and we send the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// Sort ranges and merge any which overlap or touch.
+fn coalesce(mut ranges: Vec<HttpRange>) -> Vec<HttpRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<HttpRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.start + last.length => {
+                let end = (last.start + last.length).max(range.start + range.length);
+                last.length = end - last.start;
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// Parse a Range header value against a file of the given size.
+///
+/// Malformed headers are ignored, as RFC 9110 requires.
+pub fn parse_header(value: &str, size: u64) -> RangeRequest {
+    let ranges = match http_range::HttpRange::parse(value, size) {
+        Ok(ranges) => ranges,
+        Err(HttpRangeParseError::NoOverlap) => return RangeRequest::Unsatisfiable,
+        Err(HttpRangeParseError::InvalidRange) => return RangeRequest::Full,
+    };
+
+    let ranges = coalesce(
+        ranges
+            .into_iter()
+            .map(|range| HttpRange {
+                start: range.start,
+                length: range.length,
+            })
+            .collect(),
+    );
+
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        RangeRequest::Full
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+/// Parse the Range header of a request against a file of the given size.
+pub fn parse(req: &HttpRequest, size: u64) -> RangeRequest {
+    match req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) => parse_header(value, size),
+        None => RangeRequest::Full,
+    }
+}
+
+/// Format the Content-Range value for a range of a file.
+pub fn content_range(range: &HttpRange, size: u64) -> String {
+    format!(
+        "bytes {}-{}/{}",
+        range.start,
+        range.start + range.length - 1,
+        size
+    )
+}
+
+/// Build a `multipart/byteranges` body out of several ranges of a file.
+///
+/// Returns the boundary used, the total length of the body and the body itself.
+/// Each range is only read once the client gets to it.
+pub fn multipart<F, Fut>(
+    ranges: Vec<HttpRange>,
+    size: u64,
+    content_type: &str,
+    read: F,
+) -> (String, u64, ByteStream)
+where
+    F: Fn(HttpRange) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<ByteStream, Error>> + Send + 'static,
+{
+    let boundary = nanoid!(32);
+    let headers: Vec<Bytes> = ranges
+        .iter()
+        .map(|range| {
+            Bytes::from(format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                content_range(range, size)
+            ))
+        })
+        .collect();
+
+    let footer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+    let length = headers.iter().map(|part| part.len() as u64).sum::<u64>()
+        + ranges.iter().map(|range| range.length).sum::<u64>()
+        + footer.len() as u64;
+
+    let parts = headers.into_iter().zip(ranges).map(move |(header, range)| {
+        stream::once(async move { Ok(header) }).chain(stream::once(read(range)).try_flatten())
+    });
+
+    let body = stream::iter(parts)
+        .flatten()
+        .chain(stream::once(async move { Ok(footer) }))
+        .boxed();
+
+    (boundary, length, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(value: &str, size: u64) -> Vec<(u64, u64)> {
+        match parse_header(value, size) {
+            RangeRequest::Partial(ranges) => ranges
+                .into_iter()
+                .map(|range| (range.start, range.length))
+                .collect(),
+            RangeRequest::Full => panic!("{} was served in full", value),
+            RangeRequest::Unsatisfiable => panic!("{} was unsatisfiable", value),
+        }
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(partial("bytes=0-9", 100), vec![(0, 10)]);
+        assert_eq!(partial("bytes=90-", 100), vec![(90, 10)]);
+        assert_eq!(partial("bytes=-10", 100), vec![(90, 10)]);
+        assert_eq!(partial("bytes=90-200", 100), vec![(90, 10)]);
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        for value in ["", "bytes=", "bytes=a-b", "bytes=10-5", "items=0-9", "0-9"] {
+            assert!(
+                matches!(parse_header(value, 100), RangeRequest::Full),
+                "{} was not ignored",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert!(matches!(
+            parse_header("bytes=100-", 100),
+            RangeRequest::Unsatisfiable
+        ));
+        assert!(matches!(
+            parse_header("bytes=200-300", 100),
+            RangeRequest::Unsatisfiable
+        ));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            partial("bytes=50-59,0-9,5-19", 100),
+            vec![(0, 20), (50, 10)]
+        );
+        assert_eq!(partial("bytes=0-9,10-19", 100), vec![(0, 20)]);
+        assert_eq!(partial("bytes=0-49,10-19", 100), vec![(0, 50)]);
+    }
+
+    #[test]
+    fn serves_too_many_ranges_in_full() {
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        assert!(matches!(
+            parse_header(&format!("bytes={}", many), 100),
+            RangeRequest::Full
+        ));
+
+        // Unless they merge into few enough.
+        let touching = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i, i))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        assert_eq!(partial(&format!("bytes={}", touching), 100), vec![(0, 17)]);
+    }
+
+    #[test]
+    fn formats_content_range() {
+        let range = HttpRange {
+            start: 10,
+            length: 5,
+        };
+
+        assert_eq!(content_range(&range, 100), "bytes 10-14/100");
+    }
+}
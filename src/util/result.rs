@@ -1,5 +1,6 @@
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
+use log::{debug, error};
 use serde::Serialize;
 use serde_json;
 use std::fmt::Display;
@@ -7,25 +8,110 @@ use std::fmt::Display;
 #[derive(Serialize, Debug)]
 #[serde(tag = "type")]
 pub enum Error {
-    FileTooLarge { max_size: usize },
+    FileTooLarge {
+        max_size: usize,
+    },
+    QuotaExceeded {
+        remaining_files: Option<u64>,
+        remaining_bytes: Option<u64>,
+    },
+    RateLimited {
+        /// Seconds until the request may be retried, if it ever can be.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
     ContentTypeNotAllowed,
     FileTypeNotAllowed,
     FailedToReceive,
     BlockingError,
     DatabaseError,
     MissingData,
+    UnsupportedMediaType,
+    UnsupportedVersion,
+    UploadConflict,
     UnknownTag,
+    Unauthenticated,
+    AuthUnavailable,
+    InvalidSignature,
     ProbeError,
     NotFound,
+    RangeNotSatisfiable,
+    NotSupported,
     Malware,
+    ScanUnavailable,
+    LeaseLost,
     IOError,
     S3Error,
     LabelMe,
 }
 
 impl Display for Error {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::FileTooLarge { max_size } => {
+                write!(f, "File is larger than the maximum of {} bytes.", max_size)
+            }
+            Error::QuotaExceeded { .. } => write!(f, "Upload quota for this tag exceeded."),
+            Error::RateLimited {
+                retry_after: Some(retry_after),
+            } => {
+                write!(
+                    f,
+                    "Too many requests, try again in {} seconds.",
+                    retry_after
+                )
+            }
+            Error::RateLimited { retry_after: None } => write!(f, "Too many requests."),
+            Error::ContentTypeNotAllowed => write!(f, "This content type is not allowed."),
+            Error::FileTypeNotAllowed => write!(f, "This type of file is not allowed here."),
+            Error::FailedToReceive => write!(f, "Failed to receive the upload."),
+            Error::BlockingError => write!(f, "A background task failed."),
+            Error::DatabaseError => write!(f, "A database operation failed."),
+            Error::MissingData => write!(f, "The request is missing required data."),
+            Error::UnsupportedMediaType => write!(f, "The request has the wrong content type."),
+            Error::UnsupportedVersion => write!(f, "This protocol version is not supported."),
+            Error::UploadConflict => write!(f, "The upload offset does not match."),
+            Error::UnknownTag => write!(f, "This tag does not exist."),
+            Error::Unauthenticated => write!(f, "A valid session or bot token is required."),
+            Error::AuthUnavailable => write!(f, "Unable to verify the token right now."),
+            Error::InvalidSignature => {
+                write!(f, "The URL signature is missing, invalid or expired.")
+            }
+            Error::ProbeError => write!(f, "Failed to probe the file."),
+            Error::NotFound => write!(f, "The file could not be found."),
+            Error::RangeNotSatisfiable => write!(f, "The requested range is outside the file."),
+            Error::NotSupported => write!(f, "This is not supported by the server."),
+            Error::Malware => write!(f, "The file was flagged as malware."),
+            Error::ScanUnavailable => write!(
+                f,
+                "The file could not be scanned for malware, try again later."
+            ),
+            Error::LeaseLost => write!(f, "Lost the lease while running a job."),
+            Error::IOError => write!(f, "A filesystem operation failed."),
+            Error::S3Error => write!(f, "A storage operation failed."),
+            Error::LabelMe => write!(f, "An unexpected error occurred."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub trait ResultExt<T> {
+    /// Replace the error with our own, logging the underlying cause.
+    fn context(self, error: Error) -> Result<T, Error>;
+}
+
+impl<T, E: Display> ResultExt<T> for Result<T, E> {
+    fn context(self, error: Error) -> Result<T, Error> {
+        self.map_err(|cause| {
+            if error.status_code().is_server_error() {
+                error!("{:?}: {}", error, cause);
+            } else {
+                debug!("{:?}: {}", error, cause);
+            }
+
+            error
+        })
     }
 }
 
@@ -33,27 +119,48 @@ impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match &self {
             Error::FileTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
             Error::ContentTypeNotAllowed => StatusCode::BAD_REQUEST,
             Error::FileTypeNotAllowed => StatusCode::BAD_REQUEST,
             Error::FailedToReceive => StatusCode::BAD_REQUEST,
             Error::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::MissingData => StatusCode::BAD_REQUEST,
+            Error::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            Error::UploadConflict => StatusCode::CONFLICT,
             Error::UnknownTag => StatusCode::BAD_REQUEST,
+            Error::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Error::AuthUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Error::InvalidSignature => StatusCode::FORBIDDEN,
             Error::ProbeError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            Error::NotSupported => StatusCode::NOT_IMPLEMENTED,
             Error::BlockingError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::IOError => StatusCode::INTERNAL_SERVER_ERROR,
             Error::S3Error => StatusCode::INTERNAL_SERVER_ERROR,
             Error::LabelMe => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Malware => StatusCode::FORBIDDEN,
+            Error::ScanUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Error::LeaseLost => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        let body = serde_json::to_string(&self).unwrap();
+        let mut body = serde_json::to_value(self).unwrap();
+        body["message"] = self.to_string().into();
+
+        let mut builder = HttpResponse::build(self.status_code());
+        if let Error::RateLimited {
+            retry_after: Some(retry_after),
+        } = self
+        {
+            builder.insert_header(("Retry-After", retry_after.to_string()));
+        }
 
-        HttpResponse::build(self.status_code())
+        builder
             .content_type("application/json")
-            .body(body)
+            .body(body.to_string())
     }
 }
@@ -1,7 +1,9 @@
-use crate::util::result::Error;
+use crate::util::result::{Error, ResultExt};
 
+use ipnet::IpNet;
 use s3::{creds::Credentials, Region};
 use std::env;
+use std::net::IpAddr;
 
 lazy_static! {
     // Application Settings
@@ -15,12 +17,34 @@ lazy_static! {
         env::var("AUTUMN_MONGO_DATABASE").unwrap_or_else(|_| "revolt".to_string());
     pub static ref CORS_ALLOWED_ORIGIN: String =
         env::var("AUTUMN_CORS_ALLOWED_ORIGIN").expect("Missing AUTUMN_CORS_ALLOWED_ORIGIN environment variable.");
+    pub static ref IP_HASH_SALT: String = env::var("AUTUMN_IP_HASH_SALT")
+        .ok()
+        .filter(|salt| !salt.is_empty())
+        .expect("Missing AUTUMN_IP_HASH_SALT environment variable.");
+    pub static ref TRUSTED_PROXIES: Vec<IpNet> = env::var("AUTUMN_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| {
+            proxy
+                .parse::<IpNet>()
+                .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                .expect("Invalid address in AUTUMN_TRUSTED_PROXIES.")
+        })
+        .collect();
+    pub static ref ADMIN_TOKEN: Option<String> = env::var("AUTUMN_ADMIN_TOKEN").ok();
+    pub static ref SIGNING_SECRET: Option<String> = env::var("AUTUMN_SIGNING_SECRET").ok();
     pub static ref CLAMD_HOST: String =
         env::var("CLAMD_HOST").expect("Missing CLAMD_HOST environment variable.");
 
     // Storage Settings
     pub static ref LOCAL_STORAGE_PATH: String =
         env::var("AUTUMN_LOCAL_STORAGE_PATH").unwrap_or_else(|_| "./files".to_string());
+    pub static ref CACHE_PATH: String =
+        env::var("AUTUMN_CACHE_PATH").unwrap_or_else(|_| "./cache".to_string());
+    pub static ref UPLOADS_PATH: String =
+        env::var("AUTUMN_UPLOADS_PATH").unwrap_or_else(|_| "./uploads".to_string());
     pub static ref S3_REGION: Region = Region::Custom {
         region: env::var("AUTUMN_S3_REGION").unwrap_or_else(|_| "".to_string()),
         endpoint: env::var("AUTUMN_S3_ENDPOINT").unwrap_or_else(|_| "".to_string())
@@ -31,6 +55,8 @@ lazy_static! {
     // Application Flags
     pub static ref USE_S3: bool = env::var("AUTUMN_S3_REGION").is_ok() && env::var("AUTUMN_S3_ENDPOINT").is_ok();
     pub static ref USE_CLAMD: bool = env::var("CLAMD_HOST").is_ok();
+    pub static ref STORAGE_BACKEND: String = env::var("AUTUMN_STORAGE_BACKEND")
+        .unwrap_or_else(|_| if *USE_S3 { "s3" } else { "local" }.to_string());
 }
 
 pub fn get_s3_bucket(bucket: &str) -> Result<s3::Bucket, Error> {
@@ -41,5 +67,5 @@ pub fn get_s3_bucket(bucket: &str) -> Result<s3::Bucket, Error> {
         S3_REGION.clone(),
         S3_CREDENTIALS.clone(),
     )
-    .map_err(|_| Error::S3Error)
+    .context(Error::S3Error)
 }
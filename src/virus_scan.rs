@@ -1,30 +1,211 @@
+//! Asynchronous clamd client.
+//!
+//! Connections are kept open as clamd sessions (`IDSESSION`)
+//! and reused between scans, a connection which fails is
+//! dropped and the scan retried on a fresh one.
+
+use std::path::Path;
 use std::time::Duration;
 
-use log::{error, info};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Semaphore};
 
+use crate::config::{Config, ScanFailurePolicy};
+use crate::util::result::Error;
 use crate::util::variables::{CLAMD_HOST, USE_CLAMD};
 
+/// Size of the chunks files are streamed to clamd in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+type Connection = BufReader<TcpStream>;
+
+/// Outcome of a successful scan.
+pub enum Verdict {
+    Clean,
+    /// Name of the signature which matched.
+    Infected(String),
+}
+
+/// Idle sessions ready to be reused.
+static IDLE: Lazy<Mutex<Vec<Connection>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Limits how many connections are in use at once.
+static PERMITS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(Config::global().clamd.pool_size.max(1)));
+
+/// Read a null-terminated reply, without any session request id.
+async fn read_reply(connection: &mut Connection) -> std::io::Result<String> {
+    let mut reply = vec![];
+    connection.read_until(b'\0', &mut reply).await?;
+
+    if reply.pop() != Some(b'\0') {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let reply = String::from_utf8_lossy(&reply);
+    Ok(match reply.split_once(": ") {
+        Some((id, rest)) if id.chars().all(|c| c.is_ascii_digit()) => rest.to_string(),
+        _ => reply.to_string(),
+    })
+}
+
+async fn connect() -> std::io::Result<TcpStream> {
+    let timeout = Duration::from_millis(Config::global().clamd.connect_timeout);
+    tokio::time::timeout(timeout, TcpStream::connect(CLAMD_HOST.as_str()))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
+/// Take an idle session or open a new one, returning
+/// whether the connection was reused.
+async fn checkout() -> std::io::Result<(Connection, bool)> {
+    if let Some(connection) = IDLE.lock().await.pop() {
+        return Ok((connection, true));
+    }
+
+    let mut stream = connect().await?;
+    stream.write_all(b"zIDSESSION\0").await?;
+    Ok((BufReader::new(stream), false))
+}
+
+/// Stream a file to clamd over a session and parse the verdict.
+async fn scan_once(connection: &mut Connection, path: &Path) -> std::io::Result<Verdict> {
+    let mut file = File::open(path).await?;
+    let stream = connection.get_mut();
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let length = file.read(&mut buffer).await?;
+        stream.write_all(&(length as u32).to_be_bytes()).await?;
+
+        if length == 0 {
+            break;
+        }
+
+        stream.write_all(&buffer[..length]).await?;
+    }
+
+    stream.flush().await?;
+
+    let reply = read_reply(connection).await?;
+    if let Some(signature) = reply
+        .strip_prefix("stream: ")
+        .and_then(|rest| rest.strip_suffix(" FOUND"))
+    {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else if reply == "stream: OK" {
+        Ok(Verdict::Clean)
+    } else {
+        Err(std::io::Error::other(reply))
+    }
+}
+
+/// Scan a file, retrying on a fresh connection if anything goes wrong.
+pub async fn scan(path: &Path) -> Result<Verdict, Error> {
+    let config = &Config::global().clamd;
+    let _permit = PERMITS
+        .acquire()
+        .await
+        .map_err(|_| Error::ScanUnavailable)?;
+
+    let mut attempt = 0;
+    loop {
+        let mut reused = false;
+        let result = match checkout().await {
+            Ok((mut connection, was_reused)) => {
+                reused = was_reused;
+                tokio::time::timeout(
+                    Duration::from_secs(config.timeout),
+                    scan_once(&mut connection, path),
+                )
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                .map(|verdict| (verdict, connection))
+            }
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok((verdict, connection)) => {
+                IDLE.lock().await.push(connection);
+                return Ok(verdict);
+            }
+            // clamd closes sessions left idle for too long.
+            Err(error) if reused && error.kind() != std::io::ErrorKind::TimedOut => {}
+            Err(error) if attempt < config.retries => {
+                warn!("clamd scan failed, retrying: {}", error);
+                attempt += 1;
+                tokio::time::sleep(Duration::from_millis(100 << attempt.min(6))).await;
+            }
+            Err(error) => {
+                error!("clamd scan failed: {}", error);
+                return Err(Error::ScanUnavailable);
+            }
+        }
+    }
+}
+
+/// Scan a file if clamd is enabled, applying the
+/// configured policy if clamd is unavailable.
+pub async fn check(path: &Path) -> Result<Verdict, Error> {
+    if !*USE_CLAMD {
+        return Ok(Verdict::Clean);
+    }
+
+    match scan(path).await {
+        Ok(Verdict::Infected(signature)) => {
+            info!("Rejected upload matching {}.", signature);
+            Ok(Verdict::Infected(signature))
+        }
+        Err(Error::ScanUnavailable)
+            if Config::global().clamd.on_failure == ScanFailurePolicy::Open =>
+        {
+            warn!("Accepting upload without scanning it, clamd is unavailable.");
+            Ok(Verdict::Clean)
+        }
+        result => result,
+    }
+}
+
+/// Check whether clamd responds to a ping.
+pub async fn is_available() -> bool {
+    let ping = async {
+        let mut stream = connect().await?;
+        stream.write_all(b"zPING\0").await?;
+        read_reply(&mut BufReader::new(stream)).await
+    };
+
+    let timeout = Duration::from_secs(Config::global().clamd.timeout);
+    matches!(
+        tokio::time::timeout(timeout, ping).await,
+        Ok(Ok(reply)) if reply == "PONG"
+    )
+}
+
+/// Wait for clamd in the background, logging until it's ready.
 pub fn init() {
     if *USE_CLAMD {
         info!("Waiting for clamd to be ready...");
 
-        loop {
-            let clamd_available = match revolt_clamav_client::ping_tcp(CLAMD_HOST.to_string()) {
-                Ok(ping_response) => ping_response == b"PONG\0",
-                Err(_) => false,
-            };
+        tokio::spawn(async {
+            loop {
+                if is_available().await {
+                    info!("clamd is ready, virus protection enabled!");
+                    break;
+                }
 
-            if clamd_available {
-                info!("clamd is ready, virus protection enabled!");
-                break;
-            } else {
                 error!(
                     "Could not ping clamd host at {}, retrying in 10 seconds...",
                     CLAMD_HOST.to_string()
                 );
 
-                std::thread::sleep(Duration::from_secs(10));
+                tokio::time::sleep(Duration::from_secs(10)).await;
             }
-        }
+        });
     }
 }